@@ -0,0 +1,429 @@
+// Integer arithmetic for `$((...))`, `((...))` and `let`, with C operator
+// precedence. Variables are read and assigned through the shell's table.
+use crate::shell::Shell;
+
+// Guards against variables whose values refer back to themselves
+const MAX_DEPTH: usize = 1024;
+
+// Longest operators first so `<<=` is not read as `<` `<=`
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~",
+    "?", ":", "=", "(", ")", ",",
+];
+
+// Binary operators from lowest to highest precedence, all left associative
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const ASSIGNMENTS: &[&str] = &[
+    "=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+pub fn evaluate(shell: &mut Shell, expr: &str) -> Result<i64, String> {
+    evaluate_nested(shell, expr, 0)
+}
+
+fn evaluate_nested(shell: &mut Shell, expr: &str, depth: usize) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("{}: expression recursion level exceeded", expr));
+    }
+    let mut evaluator = Evaluator {
+        shell,
+        expr,
+        tokens: tokenize(expr)?,
+        pos: 0,
+        depth,
+    };
+    if evaluator.tokens.is_empty() {
+        return Ok(0);
+    }
+    let value = evaluator.comma(true)?;
+    if evaluator.pos < evaluator.tokens.len() {
+        return Err(evaluator.error("syntax error in expression"));
+    }
+    Ok(value)
+}
+
+// Tokens paired with their byte offset, used to point at errors
+fn tokenize(expr: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr;
+    while let Some(c) = rest.chars().next() {
+        let offset = expr.len() - rest.len();
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '#' | '@')))
+                .unwrap_or(rest.len());
+            let (text, remaining) = rest.split_at(len);
+            let token = if c.is_ascii_digit() {
+                let value = parse_number(text).ok_or_else(|| {
+                    format!(
+                        "{}: value too great for base (error token is \"{}\")",
+                        expr, text
+                    )
+                })?;
+                Token::Number(value)
+            } else {
+                Token::Name(text.to_string())
+            };
+            tokens.push((token, offset));
+            rest = remaining;
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| {
+                    format!(
+                        "{}: syntax error: invalid arithmetic operator (error token is \"{}\")",
+                        expr, rest
+                    )
+                })?;
+            tokens.push((Token::Op(op), offset));
+            rest = &rest[op.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+// Decimal, `0x` hex, leading-zero octal and `base#digits` constants
+fn parse_number(text: &str) -> Option<i64> {
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        (
+            base.parse().ok().filter(|base| (2..=64).contains(base))?,
+            digits,
+        )
+    } else if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, digits)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as i64 - '0' as i64,
+            'a'..='z' => c as i64 - 'a' as i64 + 10,
+            'A'..='Z' if base <= 36 => c as i64 - 'A' as i64 + 10,
+            'A'..='Z' => c as i64 - 'A' as i64 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        if digit >= base {
+            return None;
+        }
+        value = value.wrapping_mul(base).wrapping_add(digit);
+    }
+    Some(value)
+}
+
+// Recursive descent evaluator. `eval` is false in the branches skipped by
+// `&&`, `||` and `?:`, which are parsed without side effects or errors.
+struct Evaluator<'a> {
+    shell: &'a mut Shell,
+    expr: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op(&[op]).is_none() {
+            return Err(self.error(&format!("missing `{}'", op)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> String {
+        let rest = match self.tokens.get(self.pos) {
+            Some((_, offset)) => &self.expr[*offset..],
+            None => "",
+        };
+        format!("{}: {} (error token is \"{}\")", self.expr, message, rest)
+    }
+
+    fn comma(&mut self, eval: bool) -> Result<i64, String> {
+        let mut value = self.assignment(eval)?;
+        while self.peek_op(&[","]).is_some() {
+            self.pos += 1;
+            value = self.assignment(eval)?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self, eval: bool) -> Result<i64, String> {
+        let (name, op) = match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Name(name)), Some((Token::Op(op), _))) if ASSIGNMENTS.contains(op) => {
+                (name.clone(), *op)
+            }
+            _ => return self.conditional(eval),
+        };
+        self.pos += 2;
+        let value = self.assignment(eval)?;
+        if !eval {
+            return Ok(0);
+        }
+        let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+            Some(op) => {
+                let current = self.variable(&name)?;
+                self.apply(op, current, value, eval)?
+            }
+            None => value,
+        };
+        self.assign(&name, value)
+    }
+
+    fn conditional(&mut self, eval: bool) -> Result<i64, String> {
+        let condition = self.binary(0, eval)?;
+        if self.peek_op(&["?"]).is_none() {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.assignment(eval && condition != 0)?;
+        self.expect(":")?;
+        let otherwise = self.assignment(eval && condition == 0)?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    fn binary(&mut self, level: usize, eval: bool) -> Result<i64, String> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.power(eval);
+        };
+        let mut left = self.binary(level + 1, eval)?;
+        while let Some(op) = self.peek_op(ops) {
+            self.pos += 1;
+            let eval_right = match op {
+                "||" => eval && left == 0,
+                "&&" => eval && left != 0,
+                _ => eval,
+            };
+            let right = self.binary(level + 1, eval_right)?;
+            left = self.apply(op, left, right, eval)?;
+        }
+        Ok(left)
+    }
+
+    // `**` is right associative and binds looser than unary minus
+    fn power(&mut self, eval: bool) -> Result<i64, String> {
+        let base = self.unary(eval)?;
+        if self.peek_op(&["**"]).is_none() {
+            return Ok(base);
+        }
+        self.pos += 1;
+        let exponent = self.power(eval)?;
+        self.apply("**", base, exponent, eval)
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<i64, String> {
+        let Some(op) = self.peek_op(&["!", "~", "-", "+", "++", "--"]) else {
+            return self.postfix(eval);
+        };
+        self.pos += 1;
+        if op == "++" || op == "--" {
+            let Some(Token::Name(name)) = self.peek().cloned() else {
+                return Err(self.error("syntax error: operand expected"));
+            };
+            self.pos += 1;
+            if !eval {
+                return Ok(0);
+            }
+            let value = self.variable(&name)?;
+            let value = if op == "++" {
+                value.wrapping_add(1)
+            } else {
+                value.wrapping_sub(1)
+            };
+            return self.assign(&name, value);
+        }
+        let value = self.unary(eval)?;
+        Ok(match op {
+            "!" => (value == 0) as i64,
+            "~" => !value,
+            "-" => value.wrapping_neg(),
+            _ => value,
+        })
+    }
+
+    fn postfix(&mut self, eval: bool) -> Result<i64, String> {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                let value = if eval { self.variable(&name)? } else { 0 };
+                if let Some(op) = self.peek_op(&["++", "--"]) {
+                    self.pos += 1;
+                    if eval {
+                        let delta = if op == "++" { 1 } else { -1 };
+                        self.assign(&name, value.wrapping_add(delta))?;
+                    }
+                }
+                Ok(value)
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma(eval)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.error("syntax error: operand expected")),
+        }
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64, eval: bool) -> Result<i64, String> {
+        let value = match op {
+            "||" => (left != 0 || right != 0) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                if eval {
+                    return Err(self.error("division by 0"));
+                }
+                0
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => {
+                if eval {
+                    return Err(self.error("exponent less than 0"));
+                }
+                0
+            }
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            _ => unreachable!("unknown operator {}", op),
+        };
+        Ok(value)
+    }
+
+    // Unset and empty variables are 0, other values are evaluated in turn
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        let value = self
+            .shell
+            .vars
+            .get(name)
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        match parse_number(&value) {
+            Some(number) => Ok(number),
+            None => evaluate_nested(self.shell, &value, self.depth + 1),
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<i64, String> {
+        self.shell.vars.set(name, value.to_string())?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, String> {
+        evaluate(&mut Shell::new(), expr)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("7 / 2 + 7 % 2"), Ok(4));
+        assert_eq!(eval("1 < 2 && 3 == 3 || 0"), Ok(1));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("1 << 4 | 6 ^ 3 & 1"), Ok(23));
+        assert_eq!(eval("0x1f + 010 + 2#101"), Ok(31 + 8 + 5));
+        assert_eq!(eval("0 ? 1 : 2"), Ok(2));
+        assert_eq!(eval(""), Ok(0));
+    }
+
+    #[test]
+    fn variables() {
+        let mut shell = Shell::new();
+        assert_eq!(evaluate(&mut shell, "x = 5, x += 2, x++"), Ok(7));
+        assert_eq!(shell.vars.get("x"), Some("8"));
+        assert_eq!(evaluate(&mut shell, "--x * 2"), Ok(14));
+        shell.vars.set("y", "x + 1".to_string()).unwrap();
+        assert_eq!(evaluate(&mut shell, "y"), Ok(8));
+        // Skipped branches have no side effects
+        assert_eq!(evaluate(&mut shell, "0 && (x = 100)"), Ok(0));
+        assert_eq!(evaluate(&mut shell, "1 || x++"), Ok(1));
+        assert_eq!(evaluate(&mut shell, "1 ? 2 : 1 / 0"), Ok(2));
+        assert_eq!(shell.vars.get("x"), Some("7"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            eval("1 +"),
+            Err("1 +: syntax error: operand expected (error token is \"\")".to_string())
+        );
+        assert_eq!(
+            eval("1 / 0"),
+            Err("1 / 0: division by 0 (error token is \"\")".to_string())
+        );
+        assert_eq!(
+            eval("(1 + 2"),
+            Err("(1 + 2: missing `)' (error token is \"\")".to_string())
+        );
+        assert!(eval("1 2").is_err());
+        assert!(eval("09").is_err());
+    }
+}
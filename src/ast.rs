@@ -0,0 +1,359 @@
+// Syntax tree produced by the parser.
+//
+// Words are kept as raw source text (quotes and backslashes included) so the
+// expansion phase can still tell quoted from unquoted characters.
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectKind {
+    // `<`
+    Input,
+    // `>` and `>|`
+    Output,
+    // `>>`
+    Append,
+    // `>&` and `<&`, the target is a descriptor number or `-` to close
+    DupOutput,
+    DupInput,
+    // `&>` and `&>>`, stdout and stderr to the same file
+    OutputAll,
+    AppendAll,
+    // `<<` and `<<-`, the target is the body. Quoting any part of the
+    // delimiter turns off expansion in the body.
+    HereDoc { expand: bool },
+    // `<<<`, the target is a word
+    HereString,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    // Explicit descriptor number (`2>`), `None` means the operator default
+    pub fd: Option<u32>,
+    pub kind: RedirectKind,
+    pub target: String,
+}
+
+impl Redirect {
+    pub fn fd(&self) -> u32 {
+        match self.kind {
+            RedirectKind::Input
+            | RedirectKind::DupInput
+            | RedirectKind::HereDoc { .. }
+            | RedirectKind::HereString => self.fd.unwrap_or(0),
+            _ => self.fd.unwrap_or(1),
+        }
+    }
+}
+
+// `NAME=value`, the value is a raw word
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    // Redirections after the closing keyword apply to the whole command
+    Compound(CompoundCommand, Vec<Redirect>),
+    // `name() body`, the body is a compound command
+    Function(String, Box<Command>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    // `{ list; }`
+    Group(List),
+    // `if`, then each `elif`, as condition and body
+    If {
+        branches: Vec<(List, List)>,
+        else_body: Option<List>,
+    },
+    // `until` loops while the condition fails
+    While {
+        until: bool,
+        condition: List,
+        body: List,
+    },
+    // Without `in`, the words are the positional parameters
+    For {
+        name: String,
+        words: Option<Vec<String>>,
+        body: List,
+    },
+    Case {
+        word: String,
+        items: Vec<CaseItem>,
+    },
+}
+
+// `pattern | pattern) body ;;`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<String>,
+    pub body: List,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOrList {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    // Terminated by `&`, runs as a background job
+    pub background: bool,
+}
+
+pub type List = Vec<AndOrList>;
+
+// Source-like text of a command, how jobs are shown
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(fd) = self.fd {
+            write!(f, "{}", fd)?;
+        }
+        let op = match self.kind {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::DupOutput => ">&",
+            RedirectKind::DupInput => "<&",
+            RedirectKind::OutputAll => "&>",
+            RedirectKind::AppendAll => "&>>",
+            // The body follows the command line
+            RedirectKind::HereDoc { expand } => {
+                let delimiter = heredoc_delimiter(&self.target);
+                return match expand {
+                    true => write!(f, "<<{}", delimiter),
+                    false => write!(f, "<<'{}'", delimiter),
+                };
+            }
+            RedirectKind::HereString => "<<<",
+        };
+        write!(f, "{}{}", op, self.target)
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self
+            .assignments
+            .iter()
+            .map(|assignment| format!("{}={}", assignment.name, assignment.value))
+            .chain(self.words.iter().cloned())
+            .chain(self.redirects.iter().map(Redirect::to_string))
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(simple) => simple.fmt(f),
+            Command::Compound(compound, redirects) => {
+                write!(f, "{}", compound)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Command::Function(name, body) => write!(f, "{} () {}", name, body),
+        }
+    }
+}
+
+// Delimiter for a here-document body when printing it, one that is not a
+// line of the body
+fn heredoc_delimiter(body: &str) -> String {
+    let mut delimiter = "EOF".to_string();
+    while body.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+// Here-document bodies of the commands on a line, each with its delimiter
+fn push_heredocs(text: &mut String, and_or: &AndOrList) {
+    let pipelines = std::iter::once(&and_or.first).chain(and_or.rest.iter().map(|(_, p)| p));
+    for command in pipelines.flat_map(|pipeline| &pipeline.commands) {
+        push_command_heredocs(text, command);
+    }
+}
+
+fn push_command_heredocs(text: &mut String, command: &Command) {
+    let redirects = match command {
+        Command::Simple(simple) => &simple.redirects,
+        Command::Compound(compound, redirects) => {
+            let lists: Vec<&List> = match compound {
+                CompoundCommand::Group(list) => vec![list],
+                CompoundCommand::If {
+                    branches,
+                    else_body,
+                } => branches
+                    .iter()
+                    .flat_map(|(condition, body)| [condition, body])
+                    .chain(else_body)
+                    .collect(),
+                CompoundCommand::While {
+                    condition, body, ..
+                } => vec![condition, body],
+                CompoundCommand::For { body, .. } => vec![body],
+                CompoundCommand::Case { items, .. } => {
+                    items.iter().map(|item| &item.body).collect()
+                }
+            };
+            for and_or in lists.into_iter().flatten() {
+                push_heredocs(text, and_or);
+            }
+            redirects
+        }
+        Command::Function(_, body) => return push_command_heredocs(text, body),
+    };
+    for redirect in redirects {
+        if matches!(redirect.kind, RedirectKind::HereDoc { .. }) {
+            text.push_str(&redirect.target);
+            text.push_str(&heredoc_delimiter(&redirect.target));
+            text.push('\n');
+        }
+    }
+}
+
+// Source of a function definition, as `type` shows it: one command of the
+// body per line, followed by its here-documents
+pub fn function_definition(name: &str, body: &Command) -> String {
+    let mut text = format!("{} ()\n", name);
+    match body {
+        Command::Compound(CompoundCommand::Group(list), redirects) => {
+            text.push_str("{\n");
+            for and_or in list {
+                let end = if and_or.background { " &" } else { "" };
+                text.push_str(&format!("    {}{}\n", and_or, end));
+                push_heredocs(&mut text, and_or);
+            }
+            text.push('}');
+            for redirect in redirects {
+                text.push_str(&format!(" {}", redirect));
+            }
+            text.push('\n');
+        }
+        body => {
+            text.push_str(&format!("{}\n", body));
+            push_command_heredocs(&mut text, body);
+        }
+    }
+    text
+}
+
+// Commands of a list on one line, each ended by `;` or `&`
+fn fmt_list(f: &mut fmt::Formatter<'_>, list: &List) -> fmt::Result {
+    for and_or in list {
+        let end = if and_or.background { "&" } else { ";" };
+        write!(f, " {}{}", and_or, end)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for CompoundCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompoundCommand::Group(list) => {
+                write!(f, "{{")?;
+                fmt_list(f, list)?;
+                write!(f, " }}")
+            }
+            CompoundCommand::If {
+                branches,
+                else_body,
+            } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    write!(f, "{}", if i == 0 { "if" } else { " elif" })?;
+                    fmt_list(f, condition)?;
+                    write!(f, " then")?;
+                    fmt_list(f, body)?;
+                }
+                if let Some(body) = else_body {
+                    write!(f, " else")?;
+                    fmt_list(f, body)?;
+                }
+                write!(f, " fi")
+            }
+            CompoundCommand::While {
+                until,
+                condition,
+                body,
+            } => {
+                write!(f, "{}", if *until { "until" } else { "while" })?;
+                fmt_list(f, condition)?;
+                write!(f, " do")?;
+                fmt_list(f, body)?;
+                write!(f, " done")
+            }
+            CompoundCommand::For { name, words, body } => {
+                write!(f, "for {}", name)?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do")?;
+                fmt_list(f, body)?;
+                write!(f, " done")
+            }
+            CompoundCommand::Case { word, items } => {
+                write!(f, "case {} in", word)?;
+                for item in items {
+                    let body: Vec<String> = item
+                        .body
+                        .iter()
+                        .map(|and_or| match and_or.background {
+                            true => format!(" {} &", and_or),
+                            false => format!(" {}", and_or),
+                        })
+                        .collect();
+                    write!(f, " {}){};;", item.patterns.join(" | "), body.join(";"))?;
+                }
+                write!(f, " esac")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commands: Vec<String> = self.commands.iter().map(Command::to_string).collect();
+        write!(f, "{}", commands.join(" | "))
+    }
+}
+
+impl fmt::Display for AndOrList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (connector, pipeline) in &self.rest {
+            let op = match connector {
+                Connector::And => "&&",
+                Connector::Or => "||",
+            };
+            write!(f, " {} {}", op, pipeline)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+// Brace expansion on raw words, before any other expansion. Braces inside
+// quotes, after a backslash or part of `${...}`/`$(...)` are left alone.
+
+pub fn expand(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' => i = skip_quoted(&chars, i),
+            '$' if matches!(chars.get(i + 1), Some('{' | '(')) => {
+                i = skip_balanced(&chars, i + 1);
+            }
+            '{' => {
+                if let Some((end, alternatives)) = parse_brace(&chars, i) {
+                    let prefix: String = chars[..i].iter().collect();
+                    let suffix: String = chars[end + 1..].iter().collect();
+                    let suffixes = expand(&suffix);
+                    let mut words = Vec::new();
+                    for alternative in alternatives {
+                        for middle in expand(&alternative) {
+                            for suffix in &suffixes {
+                                words.push(format!("{}{}{}", prefix, middle, suffix));
+                            }
+                        }
+                    }
+                    return words;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![word.to_string()]
+}
+
+// Index of the closing quote of the quoted text starting at `start`
+fn skip_quoted(chars: &[char], start: usize) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() && chars[i] != quote {
+        if quote == '"' && chars[i] == '\\' {
+            i += 1;
+        }
+        i += 1;
+    }
+    i
+}
+
+// Index of the bracket closing the one at `start`
+fn skip_balanced(chars: &[char], start: usize) -> usize {
+    let (open, close) = (chars[start], if chars[start] == '{' { '}' } else { ')' });
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' => i = skip_quoted(chars, i),
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    i
+}
+
+// The closing brace and the alternatives of a `{a,b}` or `{x..y}` starting
+// at `start`, `None` when the braces are taken literally
+fn parse_brace(chars: &[char], start: usize) -> Option<(usize, Vec<String>)> {
+    let mut depth = 0;
+    let mut commas = vec![];
+    let mut i = start + 1;
+    let end = loop {
+        match chars.get(i)? {
+            '\\' => i += 1,
+            '\'' | '"' => i = skip_quoted(chars, i),
+            '$' if matches!(chars.get(i + 1), Some('{' | '(')) => {
+                i = skip_balanced(chars, i + 1);
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => break i,
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    };
+
+    if commas.is_empty() {
+        let content: String = chars[start + 1..end].iter().collect();
+        return sequence(&content).map(|items| (end, items));
+    }
+    let mut alternatives = vec![];
+    let mut from = start + 1;
+    for to in commas.into_iter().chain([end]) {
+        alternatives.push(chars[from..to].iter().collect());
+        from = to + 1;
+    }
+    Some((end, alternatives))
+}
+
+// `x..y[..step]` with integers or single letters
+fn sequence(content: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = content.split("..").collect();
+    let (first, last, step) = match parts.as_slice() {
+        [first, last] => (*first, *last, 1),
+        [first, last, step] => (*first, *last, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as usize;
+
+    if let (Ok(from), Ok(to)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        // A leading zero on either end pads every number to the same width
+        let padded = |text: &str| {
+            let digits = text.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(first) || padded(last) {
+            first.len().max(last.len())
+        } else {
+            0
+        };
+        let numbers: Vec<i64> = if from <= to {
+            (from..=to).step_by(step).collect()
+        } else {
+            (to..=from).rev().step_by(step).collect()
+        };
+        return Some(
+            numbers
+                .into_iter()
+                .map(|n| format!("{:0width$}", n, width = width))
+                .collect(),
+        );
+    }
+
+    let (mut from_chars, mut to_chars) = (first.chars(), last.chars());
+    match (
+        from_chars.next(),
+        from_chars.next(),
+        to_chars.next(),
+        to_chars.next(),
+    ) {
+        (Some(from), None, Some(to), None)
+            if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() =>
+        {
+            let letters: Vec<char> = if from <= to {
+                (from..=to).step_by(step).collect()
+            } else {
+                (to..=from).rev().step_by(step).collect()
+            };
+            Some(letters.into_iter().map(String::from).collect())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(
+            expand("src/{bin,lib,tests}"),
+            ["src/bin", "src/lib", "src/tests"]
+        );
+        assert_eq!(expand("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand("x{a,{b,c}d}"), ["xa", "xbd", "xcd"]);
+        assert_eq!(expand("{a,}z"), ["az", "z"]);
+        assert_eq!(expand("{{a,b}}"), ["{a}", "{b}"]);
+        // Literal braces
+        assert_eq!(expand("{a}"), ["{a}"]);
+        assert_eq!(expand("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(expand("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand("${x}"), ["${x}"]);
+        assert_eq!(expand("{\"a,b\",c}"), ["\"a,b\"", "c"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand("{1..5}"), ["1", "2", "3", "4", "5"]);
+        assert_eq!(expand("{5..1..2}"), ["5", "3", "1"]);
+        assert_eq!(expand("{08..11}"), ["08", "09", "10", "11"]);
+        assert_eq!(expand("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expand("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(expand("{c..a}"), ["c", "b", "a"]);
+        assert_eq!(expand("{1..a}"), ["{1..a}"]);
+    }
+}
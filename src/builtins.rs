@@ -1,24 +1,82 @@
+use crate::arithmetic;
+use crate::ast::function_definition;
+use crate::jobs::State;
+use crate::parser::RESERVED_WORDS;
+use crate::redirect::error_message;
+use crate::shell::{Jump, Shell};
 use crate::utils::*;
-use std::env;
-use std::fs;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::io::{self, Write};
+use crate::variables::*;
+use std::io::{self, prelude::*};
 use std::path::Path;
 
+// Commands that run in-process instead of being spawned
+pub const BUILTINS: &[&str] = &[
+    ":", "bg", "break", "cd", "continue", "disown", "echo", "exit", "export", "false", "fg",
+    "history", "jobs", "let", "local", "pwd", "read", "readonly", "return", "set", "shopt", "true",
+    "type", "unset",
+];
+
+// Standard streams of a builtin: the terminal or redirect targets when it
+// runs in the shell, pipe ends when it is a pipeline stage
+pub struct Io<'a> {
+    pub stdin: &'a mut dyn Read,
+    pub stdout: &'a mut dyn Write,
+    pub stderr: &'a mut dyn Write,
+}
+
+#[derive(Default)]
 pub struct Builtins;
 
 impl Builtins {
     pub fn new() -> Self {
         Builtins
     }
-    pub fn execute(&self, cmd: &str, args: &[String]) -> Result<String, ErrorKind> {
+    pub fn is_builtin(cmd: &str) -> bool {
+        BUILTINS.contains(&cmd)
+    }
+    // Run a builtin against its streams, returning its exit status
+    pub fn run(
+        &self,
+        shell: &mut Shell,
+        cmd: &str,
+        args: &[String],
+        io: &mut Io,
+    ) -> io::Result<i32> {
+        let output = match cmd {
+            "read" => self.read(shell, args, io.stdin),
+            _ => self.execute(shell, cmd, args),
+        };
+        write_result(output, io)
+    }
+    pub fn execute(
+        &self,
+        shell: &mut Shell,
+        cmd: &str,
+        args: &[String],
+    ) -> Result<String, ErrorKind> {
         match cmd {
+            ":" | "true" => Ok(String::new()),
+            "false" => Err(ErrorKind::CompleteFailure(String::new())),
             "echo" => self.echo(args),
+            "exit" => self.exit(shell, args.iter().next().map(|x| x.as_str())),
             "pwd" => self.pwd(),
-            "cd" => self.cd(args.iter().next().map(|x| x.as_str()).unwrap_or("~")),
-            "type" => self._type(args.iter().map(|x| x.as_str()).next()),
-            "cat" => self.cat(args),
+            "cd" => self.cd(shell, args.iter().next().map(|x| x.as_str())),
+            "shopt" => self.shopt(shell, args),
+            "set" => self.set(shell, args),
+            "type" => self._type(shell, args.iter().map(|x| x.as_str()).next()),
+            "export" => self.export(shell, args),
+            "let" => self.let_(shell, args),
+            "readonly" => self.readonly(shell, args),
+            "unset" => self.unset(shell, args),
+            "jobs" => self.jobs(shell),
+            "bg" => self.bg(shell, args),
+            "disown" => self.disown(shell, args),
+            "break" | "continue" => self.loop_control(shell, cmd, args),
+            "return" => self.return_(shell, args),
+            "local" => self.local(shell, args),
+            // Only runs in-process as a lone command, where it is handled
+            // by the executor
+            "fg" => Err(ErrorKind::CompleteFailure("fg: no job control".to_string())),
             "history" => self.history(args.iter().next().map(|x| x.as_str())),
             _ => Err(ErrorKind::CompleteFailure(format!(
                 "{}: command not found",
@@ -48,38 +106,464 @@ impl Builtins {
         Ok(String::new())
     }
 
-    pub fn echo(&self, args: &[String]) -> Result<String, ErrorKind> {
-        Ok(format!("{}\n", args.join(" ")))
+    pub fn exit(&self, shell: &mut Shell, args: Option<&str>) -> Result<String, ErrorKind> {
+        match args {
+            Some(arg) => match arg.parse::<i32>() {
+                Ok(code) => shell.exit_requested = Some(code & 0xff),
+                Err(_) => {
+                    shell.exit_requested = Some(2);
+                    return Err(ErrorKind::CompleteFailure(format!(
+                        "exit: {}: numeric argument required",
+                        arg
+                    )));
+                }
+            },
+            None => shell.exit_requested = Some(shell.last_status),
+        }
+        Ok(String::new())
     }
-    pub fn cat(&self, args: &[String]) -> Result<String, ErrorKind> {
-        use std::fs::File;
-        use std::path::Path;
-
-        let mut result = vec![];
-        let mut error_result = vec![];
-        for file_name in args {
-            let path = Path::new(&file_name);
-            let display = path.display();
 
-            if let Ok(mut file) = File::open(&path) {
-                let mut s = String::new();
+    // Status is 0 when the last expression is non-zero, like `((...))`
+    pub fn let_(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        if args.is_empty() {
+            return Err(ErrorKind::CompleteFailure(
+                "let: expression expected".to_string(),
+            ));
+        }
+        let mut value = 0;
+        for arg in args {
+            value = arithmetic::evaluate(shell, arg)
+                .map_err(|error| ErrorKind::CompleteFailure(format!("let: {}", error)))?;
+        }
+        if value == 0 {
+            return Err(ErrorKind::CompleteFailure(String::new()));
+        }
+        Ok(String::new())
+    }
 
-                match file.read_to_string(&mut s) {
-                    Err(why) => panic!("couldn't read {}: {}", display, why),
-                    Ok(_) => result.push(s),
+    pub fn export(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let unexport = args.first().is_some_and(|arg| arg == "-n");
+        let names: Vec<&String> = args.iter().filter(|arg| !arg.starts_with('-')).collect();
+        if names.is_empty() {
+            // `export` and `export -p` list the exported variables
+            let lines: Vec<String> = shell
+                .vars
+                .sorted()
+                .into_iter()
+                .filter(|(_, var)| var.exported)
+                .map(|(name, var)| declaration("-x", name, var) + "\n")
+                .collect();
+            return Ok(lines.concat());
+        }
+        let mut errors = vec![];
+        for arg in names {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                errors.push(format!("export: `{}': not a valid identifier\n", arg));
+                continue;
+            }
+            if let Some(value) = value {
+                if let Err(error) = shell.vars.set(name, value.to_string()) {
+                    errors.push(format!("export: {}\n", error));
+                    continue;
                 }
+            }
+            if unexport {
+                shell.vars.unexport(name);
             } else {
-                error_result.push(format!("cat: {}: No such file or directory", file_name));
+                shell.vars.export(name);
+            }
+        }
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
+    }
+
+    pub fn readonly(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let names: Vec<&String> = args.iter().filter(|arg| !arg.starts_with('-')).collect();
+        if names.is_empty() {
+            let lines: Vec<String> = shell
+                .vars
+                .sorted()
+                .into_iter()
+                .filter(|(_, var)| var.readonly)
+                .map(|(name, var)| declaration("-r", name, var) + "\n")
+                .collect();
+            return Ok(lines.concat());
+        }
+        let mut errors = vec![];
+        for arg in names {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                errors.push(format!("readonly: `{}': not a valid identifier\n", arg));
+                continue;
+            }
+            if let Some(value) = value {
+                if let Err(error) = shell.vars.set(name, value.to_string()) {
+                    errors.push(format!("readonly: {}\n", error));
+                    continue;
+                }
+            }
+            shell.vars.set_readonly(name);
+        }
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
+    }
+
+    pub fn shopt(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let (setting, names) = match args.first().map(|arg| arg.as_str()) {
+            Some("-s") => (Some(true), &args[1..]),
+            Some("-u") => (Some(false), &args[1..]),
+            _ => (None, args),
+        };
+        let names: Vec<&str> = if names.is_empty() {
+            SHOPT_OPTIONS.to_vec()
+        } else {
+            names.iter().map(|name| name.as_str()).collect()
+        };
+        let mut output = String::new();
+        let mut errors = vec![];
+        for name in names {
+            let Some(option) = shopt_option(shell, name) else {
+                errors.push(format!("shopt: {}: invalid shell option name\n", name));
+                continue;
+            };
+            match setting {
+                Some(value) => *option = value,
+                None => {
+                    let state = if *option { "on" } else { "off" };
+                    output.push_str(&format!("{}\t{}\n", name, state));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
+    }
+
+    // `set -o name` and `set +o name` turn options on and off, a lone `-o`
+    // or `+o` lists them. Arguments after `--` become the positional
+    // parameters.
+    pub fn set(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let mut output = String::new();
+        let mut errors = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let setting = match arg.as_str() {
+                "-o" => true,
+                "+o" => false,
+                "--" => {
+                    shell.positional = args.cloned().collect();
+                    break;
+                }
+                _ => {
+                    errors.push(format!("set: {}: invalid option\n", arg));
+                    continue;
+                }
+            };
+            let Some(name) = args.next() else {
+                for name in SET_OPTIONS {
+                    let value = *set_option(shell, name).unwrap();
+                    output.push_str(&match (setting, value) {
+                        (true, true) => format!("{:<15}\ton\n", name),
+                        (true, false) => format!("{:<15}\toff\n", name),
+                        (false, true) => format!("set -o {}\n", name),
+                        (false, false) => format!("set +o {}\n", name),
+                    });
+                }
+                continue;
+            };
+            match set_option(shell, name) {
+                Some(option) => *option = setting,
+                None => errors.push(format!("set: {}: invalid option name\n", name)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(ErrorKind::PartialSuccess(PartialSuccess {
+                success_data: output,
+                error_info: errors.concat(),
+            }))
+        }
+    }
+
+    pub fn unset(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let mut errors = vec![];
+        for name in args.iter().filter(|arg| !arg.starts_with('-')) {
+            if !is_valid_name(name) {
+                errors.push(format!("unset: `{}': not a valid identifier\n", name));
+            } else if let Err(error) = shell.vars.unset(name) {
+                errors.push(format!("unset: {}\n", error));
+            }
+        }
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
+    }
+
+    // `break [n]` and `continue [n]` for the n-th enclosing loop
+    pub fn loop_control(
+        &self,
+        shell: &mut Shell,
+        cmd: &str,
+        args: &[String],
+    ) -> Result<String, ErrorKind> {
+        let count = match args.first() {
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(count) if count > 0 => count as usize,
+                Ok(_) => {
+                    return Err(ErrorKind::CompleteFailure(format!(
+                        "{}: {}: loop count out of range",
+                        cmd, arg
+                    )))
+                }
+                Err(_) => {
+                    return Err(ErrorKind::CompleteFailure(format!(
+                        "{}: {}: numeric argument required",
+                        cmd, arg
+                    )))
+                }
+            },
+            None => 1,
+        };
+        if shell.loop_depth == 0 {
+            return Err(ErrorKind::CompleteFailure(format!(
+                "{}: only meaningful in a `for', `while', or `until' loop",
+                cmd
+            )));
+        }
+        // More than the loops there are leaves all of them
+        let count = count.min(shell.loop_depth);
+        shell.jump = Some(match cmd {
+            "break" => Jump::Break(count),
+            _ => Jump::Continue(count),
+        });
+        Ok(String::new())
+    }
+
+    // `return [n]` ends the function with status n, or that of the last
+    // command
+    pub fn return_(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        if shell.function_depth == 0 {
+            return Err(ErrorKind::CompleteFailure(
+                "return: can only `return' from a function or sourced script".to_string(),
+            ));
+        }
+        let status = match args.first() {
+            Some(arg) => {
+                arg.parse::<i32>().map_err(|_| {
+                    ErrorKind::CompleteFailure(format!(
+                        "return: {}: numeric argument required",
+                        arg
+                    ))
+                })? & 0xff
+            }
+            None => shell.last_status,
+        };
+        shell.jump = Some(Jump::Return(status));
+        Ok(String::new())
+    }
+
+    // `local NAME[=value]...`, variables that last until the function returns
+    pub fn local(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let mut errors = vec![];
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                errors.push(format!("local: `{}': not a valid identifier\n", arg));
+                continue;
+            }
+            let declared = shell.vars.make_local(name).and_then(|()| match value {
+                Some(value) => shell.vars.set(name, value.to_string()),
+                None => Ok(()),
+            });
+            if let Err(error) = declared {
+                errors.push(format!("local: {}\n", error));
+            }
+        }
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
+    }
+
+    pub fn jobs(&self, shell: &mut Shell) -> Result<String, ErrorKind> {
+        Ok(shell
+            .jobs
+            .list()
+            .into_iter()
+            .map(|line| line + "\n")
+            .collect())
+    }
+
+    // `bg [job]`: continue a stopped job in the background
+    pub fn bg(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let id = shell
+            .jobs
+            .find(args.first().map(String::as_str))
+            .map_err(|error| ErrorKind::CompleteFailure(format!("bg: {}", error)))?;
+        let mark = shell.jobs.mark(id);
+        let job = shell.jobs.get_mut(id).unwrap();
+        if job.state() == State::Running {
+            return Err(ErrorKind::CompleteFailure(format!(
+                "bg: job {} already in background",
+                id
+            )));
+        }
+        job.resume();
+        Ok(format!("[{}]{} {} &\n", id, mark, job.command))
+    }
+
+    // `disown [job...]`: forget jobs without touching their processes
+    pub fn disown(&self, shell: &mut Shell, args: &[String]) -> Result<String, ErrorKind> {
+        let specs: Vec<Option<&str>> = if args.is_empty() {
+            vec![None]
+        } else {
+            args.iter().map(|arg| Some(arg.as_str())).collect()
+        };
+        let mut errors = vec![];
+        for spec in specs {
+            match shell.jobs.find(spec) {
+                Ok(id) => {
+                    shell.jobs.remove(id);
+                }
+                Err(error) => errors.push(format!("disown: {}\n", error)),
             }
         }
-        process_partial_results(result.concat(), error_result.concat())
+        if errors.is_empty() {
+            Ok(String::new())
+        } else {
+            Err(ErrorKind::CompleteFailure(errors.concat()))
+        }
     }
-    pub fn cd(&self, path: &str) -> Result<String, ErrorKind> {
-        let home_env = std::env::var("HOME").unwrap();
-        if path == "" || path == " " || path == "~" {
+
+    // `read [-r] [name...]`: one line of input split on IFS, the last name
+    // taking the rest of it. Fails at end of input.
+    pub fn read(
+        &self,
+        shell: &mut Shell,
+        args: &[String],
+        stdin: &mut dyn Read,
+    ) -> Result<String, ErrorKind> {
+        let raw = args.first().is_some_and(|arg| arg == "-r");
+        let mut names: Vec<&str> = args.iter().skip(raw as usize).map(String::as_str).collect();
+        let whole_line = names.is_empty();
+        if whole_line {
+            names.push("REPLY");
+        }
+        if let Some(name) = names.iter().find(|name| !is_valid_name(name)) {
+            return Err(ErrorKind::CompleteFailure(format!(
+                "read: `{}': not a valid identifier",
+                name
+            )));
+        }
+
+        // One byte at a time so nothing past the line is consumed. Unless
+        // `-r`, a backslash quotes the next character and joins lines.
+        let mut line = Vec::new();
+        let mut quoted = Vec::new();
+        let mut escaped = false;
+        let mut complete = false;
+        let mut byte = [0u8];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) if escaped => {
+                    escaped = false;
+                    if byte[0] != b'\n' {
+                        line.push(byte[0]);
+                        quoted.push(true);
+                    }
+                }
+                Ok(_) if byte[0] == b'\\' && !raw => escaped = true,
+                Ok(_) if byte[0] == b'\n' => {
+                    complete = true;
+                    break;
+                }
+                Ok(_) => {
+                    line.push(byte[0]);
+                    quoted.push(false);
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+                    return Err(ErrorKind::CompleteFailure(String::new()))
+                }
+                Err(error) => {
+                    return Err(ErrorKind::CompleteFailure(format!(
+                        "read: {}",
+                        error_message(&error)
+                    )))
+                }
+            }
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let chars: Vec<(char, bool)> = if text.len() == line.len() {
+            text.chars().zip(quoted).collect()
+        } else {
+            text.chars().map(|c| (c, false)).collect()
+        };
+        let values = if whole_line {
+            vec![chars.iter().map(|(c, _)| c).collect()]
+        } else {
+            let ifs = shell.vars.get("IFS").unwrap_or(" \t\n").to_string();
+            split_line(&chars, &ifs, names.len())
+        };
+        let mut errors = vec![];
+        for (name, value) in names.iter().zip(values) {
+            if let Err(error) = shell.vars.set(name, value) {
+                errors.push(format!("read: {}\n", error));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ErrorKind::CompleteFailure(errors.concat()));
+        }
+        if !complete {
+            return Err(ErrorKind::CompleteFailure(String::new()));
+        }
+        Ok(String::new())
+    }
+
+    pub fn echo(&self, args: &[String]) -> Result<String, ErrorKind> {
+        Ok(format!("{}\n", args.join(" ")))
+    }
+    // Keeps `PWD` and `OLDPWD` up to date for `~+` and `~-`
+    pub fn cd(&self, shell: &mut Shell, path: Option<&str>) -> Result<String, ErrorKind> {
+        let home = shell.vars.get("HOME").unwrap_or_default().to_string();
+        let previous = std::env::current_dir().ok();
+        let output = self.change_directory(path.unwrap_or(&home), &home)?;
+        if let Some(previous) = previous {
+            let _ = shell.vars.set("OLDPWD", previous.display().to_string());
+        }
+        if let Ok(current) = std::env::current_dir() {
+            let _ = shell.vars.set("PWD", current.display().to_string());
+        }
+        Ok(output)
+    }
+    fn change_directory(&self, path: &str, home_env: &str) -> Result<String, ErrorKind> {
+        if path.is_empty() || path == " " {
             // Go home on empty path
             let target = Path::new(&home_env);
-            match std::env::set_current_dir(&target) {
+            match std::env::set_current_dir(target) {
                 Err(_) => Err(ErrorKind::CompleteFailure(format!(
                     "cd: {}: No such file or directory",
                     target.display()
@@ -90,7 +574,7 @@ impl Builtins {
             // Handle absolute paths
             let target = Path::new(&path);
 
-            match std::env::set_current_dir(&target) {
+            match std::env::set_current_dir(target) {
                 Err(_) => Err(ErrorKind::CompleteFailure(format!(
                     "cd: {}: No such file or directory",
                     target.display()
@@ -123,7 +607,7 @@ impl Builtins {
 
             let target = Path::new(&final_destination);
 
-            match std::env::set_current_dir(&target) {
+            match std::env::set_current_dir(target) {
                 Err(_) => Err(ErrorKind::CompleteFailure(format!(
                     "cd: {}: No such file or directory",
                     target.display()
@@ -140,19 +624,22 @@ impl Builtins {
                 .display()
         ))
     }
-    pub fn _type(&self, path: Option<&str>) -> Result<String, ErrorKind> {
+    pub fn _type(&self, shell: &Shell, path: Option<&str>) -> Result<String, ErrorKind> {
         if let Some(argument) = path {
-            if argument == "cd"
-                || argument == "echo"
-                || argument == "exit"
-                || argument == "type"
-                || argument == "pwd"
-                || argument == "history"
-            // || argument == "cat"
-            {
+            if RESERVED_WORDS.contains(&argument) {
+                Ok(format!("{argument} is a shell keyword"))
+            } else if let Some(body) = shell.functions.get(argument) {
+                let definition = function_definition(argument, body);
+                Ok(format!(
+                    "{argument} is a function\n{}",
+                    definition.trim_end()
+                ))
+            } else if Builtins::is_builtin(argument) {
                 Ok(format!("{argument} is a shell builtin"))
             } else if let Some(found) =
-                std::env::var("PATH")
+                shell
+                    .vars
+                    .get("PATH")
                     .unwrap_or_default()
                     .split(":")
                     .find(|path| {
@@ -179,3 +666,104 @@ impl Builtins {
         }
     }
 }
+
+const SHOPT_OPTIONS: &[&str] = &["failglob", "nullglob"];
+const SET_OPTIONS: &[&str] = &["pipefail"];
+
+// Write a builtin's output and errors, each ending with a newline. The
+// status is 1 when the builtin failed.
+pub fn write_result(output: Result<String, ErrorKind>, io: &mut Io) -> io::Result<i32> {
+    let (out, err, status) = match output {
+        Ok(out) => (out, String::new(), 0),
+        Err(ErrorKind::CompleteFailure(err)) => (String::new(), err, 1),
+        Err(ErrorKind::PartialSuccess(partial)) => (partial.success_data, partial.error_info, 1),
+    };
+    for (mut text, writer) in [(out, &mut *io.stdout), (err, &mut *io.stderr)] {
+        if text.is_empty() {
+            continue;
+        }
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        writer.write_all(text.as_bytes())?;
+        writer.flush()?;
+    }
+    Ok(status)
+}
+
+// Fields of a `read` line, one per name: runs of IFS whitespace and single
+// other IFS characters separate them, quoted characters never do
+fn split_line(chars: &[(char, bool)], ifs: &str, count: usize) -> Vec<String> {
+    let is_space = |&(c, quoted): &(char, bool)| !quoted && c.is_whitespace() && ifs.contains(c);
+    let is_delimiter = |&(c, quoted): &(char, bool)| !quoted && ifs.contains(c);
+    let mut values = vec![];
+    let mut i = 0;
+    while i < chars.len() && is_space(&chars[i]) {
+        i += 1;
+    }
+    while values.len() + 1 < count && i < chars.len() {
+        let start = i;
+        while i < chars.len() && !is_delimiter(&chars[i]) {
+            i += 1;
+        }
+        values.push(chars[start..i].iter().map(|(c, _)| c).collect());
+        // The separator: whitespace around at most one other IFS character
+        while i < chars.len() && is_space(&chars[i]) {
+            i += 1;
+        }
+        if i < chars.len() && is_delimiter(&chars[i]) {
+            i += 1;
+            while i < chars.len() && is_space(&chars[i]) {
+                i += 1;
+            }
+        }
+    }
+    let mut rest = &chars[i.min(chars.len())..];
+    while rest.last().is_some_and(is_space) {
+        rest = &rest[..rest.len() - 1];
+    }
+    values.push(rest.iter().map(|(c, _)| c).collect());
+    values.resize(count, String::new());
+    values
+}
+
+fn shopt_option<'a>(shell: &'a mut Shell, name: &str) -> Option<&'a mut bool> {
+    match name {
+        "failglob" => Some(&mut shell.failglob),
+        "nullglob" => Some(&mut shell.nullglob),
+        _ => None,
+    }
+}
+
+fn set_option<'a>(shell: &'a mut Shell, name: &str) -> Option<&'a mut bool> {
+    match name {
+        "pipefail" => Some(&mut shell.pipefail),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str, ifs: &str, count: usize) -> Vec<String> {
+        let chars: Vec<(char, bool)> = line.chars().map(|c| (c, false)).collect();
+        split_line(&chars, ifs, count)
+    }
+
+    #[test]
+    fn read_fields() {
+        assert_eq!(split("  a  b c  ", " \t\n", 2), ["a", "b c"]);
+        assert_eq!(split("a b", " \t\n", 3), ["a", "b", ""]);
+        assert_eq!(split("x:y::z", ":", 4), ["x", "y", "", "z"]);
+        assert_eq!(split("x : y", " :", 2), ["x", "y"]);
+        let quoted = vec![
+            ('a', false),
+            (' ', true),
+            ('b', false),
+            (' ', false),
+            ('c', false),
+        ];
+        assert_eq!(split_line(&quoted, " ", 2), ["a b", "c"]);
+    }
+}
@@ -0,0 +1,992 @@
+// Word expansion: turns raw words from the parser into command arguments.
+use crate::arithmetic;
+use crate::ast::List;
+use crate::brace;
+use crate::glob;
+use crate::jobs;
+use crate::parser;
+use crate::pattern;
+use crate::shell::Shell;
+use crate::utils::{execute_list, exit_code};
+use crate::variables::{is_valid_name, split_assignment};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::iter::Peekable;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::str::Chars;
+
+// Tilde, parameter and arithmetic expansion, command substitution and
+// quote removal on a single raw word, without field splitting
+pub fn expand_word(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let word = expand_tildes(shell, word, false);
+    Ok(expand(shell, &word, Mode::Word)?.concat())
+}
+
+// Like `expand_word`, but quoted pattern characters stay escaped so they
+// only match themselves
+pub fn expand_pattern(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let word = expand_tildes(shell, word, false);
+    Ok(expand(shell, &word, Mode::Pattern)?.concat())
+}
+
+// Value of a `NAME=value` assignment, where a tilde prefix may also follow
+// any unquoted `:`
+pub fn expand_assignment(shell: &mut Shell, value: &str) -> Result<String, String> {
+    let value = expand_tildes(shell, value, true);
+    Ok(expand(shell, &value, Mode::Word)?.concat())
+}
+
+// Here-document body with parameter and arithmetic expansion and command
+// substitution
+pub fn expand_heredoc(shell: &mut Shell, body: &str) -> Result<String, String> {
+    Ok(expand(shell, body, Mode::HereDoc)?.concat())
+}
+
+// Expand command words: brace expansion, then splitting unquoted expansion
+// results into fields and replacing fields with unquoted wildcards by the
+// paths they match
+pub fn expand_words(shell: &mut Shell, words: &[String]) -> Result<Vec<String>, String> {
+    let mut result = Vec::new();
+    for word in words.iter().flat_map(|word| brace::expand(word)) {
+        // Assignment-like arguments (`export PATH=~/bin`) get tildes after `=`
+        let word = match split_assignment(&word) {
+            Some((name, value)) => format!("{}={}", name, expand_tildes(shell, value, true)),
+            None => expand_tildes(shell, &word, false),
+        };
+        for field in expand(shell, &word, Mode::Fields)? {
+            result.extend(pathname_expansion(shell, &field)?);
+        }
+    }
+    Ok(result)
+}
+
+// Replace unquoted tilde prefixes at the start of the word (and after each
+// `:` in assignments) with the directory they name, single quoted so the
+// result is not expanded further
+fn expand_tildes(shell: &Shell, word: &str, assignment: bool) -> String {
+    let mut result = String::new();
+    let mut quote_char = None;
+    let mut at_prefix = true;
+    let mut rest = word;
+    while let Some(c) = rest.chars().next() {
+        if at_prefix && c == '~' {
+            let end = rest[1..]
+                .find(|c| c == '/' || (assignment && c == ':'))
+                .map_or(rest.len(), |end| end + 1);
+            if let Some(dir) = tilde_directory(shell, &rest[1..end]) {
+                result.push('\'');
+                result.push_str(&dir.replace('\'', "'\\''"));
+                result.push('\'');
+                rest = &rest[end..];
+                at_prefix = false;
+                continue;
+            }
+        }
+        let mut len = c.len_utf8();
+        match (c, quote_char) {
+            // The escaped character is copied along with the backslash
+            ('\\', None | Some('"')) => {
+                len += rest[1..].chars().next().map_or(0, char::len_utf8);
+            }
+            ('\'' | '"', None) => quote_char = Some(c),
+            ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
+            _ => {}
+        }
+        at_prefix = assignment && quote_char.is_none() && c == ':';
+        result.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    result
+}
+
+// Directory named by the text after a `~`, `None` leaves the prefix as is
+fn tilde_directory(shell: &Shell, name: &str) -> Option<String> {
+    // Quoted or escaped characters make the whole prefix literal
+    if name.contains(['\'', '"', '\\', '$', '`']) {
+        return None;
+    }
+    match name {
+        "" => match shell.vars.get("HOME") {
+            Some(home) => Some(home.to_string()),
+            None => passwd_home(unsafe { libc::getpwuid(libc::getuid()) }),
+        },
+        "+" => shell.vars.get("PWD").map(str::to_string),
+        "-" => shell.vars.get("OLDPWD").map(str::to_string),
+        user => {
+            let user = CString::new(user).ok()?;
+            passwd_home(unsafe { libc::getpwnam(user.as_ptr()) })
+        }
+    }
+}
+
+fn passwd_home(entry: *const libc::passwd) -> Option<String> {
+    if entry.is_null() {
+        return None;
+    }
+    let dir = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    Some(dir.to_string_lossy().into_owned())
+}
+
+// Paths matched by a field in pattern form, or the field itself when it is
+// not a glob or matches nothing
+fn pathname_expansion(shell: &Shell, field: &str) -> Result<Vec<String>, String> {
+    if !pattern::has_wildcards(field) {
+        return Ok(vec![pattern::unescape(field)]);
+    }
+    let paths = glob::expand(field);
+    if !paths.is_empty() {
+        Ok(paths)
+    } else if shell.failglob {
+        Err(format!("no match: {}", pattern::unescape(field)))
+    } else if shell.nullglob {
+        Ok(vec![])
+    } else {
+        Ok(vec![pattern::unescape(field)])
+    }
+}
+
+// Expanded text is collected into fields: unquoted expansion results are
+// split on IFS, everything else is appended to the current field
+struct Fields {
+    fields: Vec<String>,
+    current: String,
+    // A quoted empty string still makes a field
+    has_current: bool,
+    as_pattern: bool,
+    // IFS characters, `None` when field splitting is off
+    ifs: Option<String>,
+}
+
+impl Fields {
+    fn push_literal(&mut self, c: char) {
+        self.has_current = true;
+        self.current.push(c);
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.has_current = true;
+        if self.as_pattern {
+            self.current.push_str(&pattern::escape(text));
+        } else {
+            self.current.push_str(text);
+        }
+    }
+
+    fn push_expanded(&mut self, text: &str) {
+        let Some(ifs) = self.ifs.take() else {
+            self.has_current |= !text.is_empty();
+            self.current.push_str(text);
+            return;
+        };
+        let mut after_whitespace = false;
+        for c in text.chars() {
+            if !ifs.contains(c) {
+                // Wildcards in unquoted results stay active, backslashes do not
+                if self.as_pattern && c == '\\' {
+                    self.current.push('\\');
+                }
+                self.push_literal(c);
+                after_whitespace = false;
+            } else if c.is_whitespace() {
+                // Runs of IFS whitespace separate fields but never make empty ones
+                if self.has_current {
+                    self.end_field();
+                    after_whitespace = true;
+                }
+            } else {
+                if !after_whitespace {
+                    self.end_field();
+                }
+                after_whitespace = false;
+            }
+        }
+        self.ifs = Some(ifs);
+    }
+
+    // Positional parameters of a quoted `$@`, each in a field of its own.
+    // With none, `"$@"` alone makes no field.
+    fn push_params(&mut self, params: &[String]) {
+        if params.is_empty() && self.current.is_empty() {
+            self.has_current = false;
+        }
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.end_field();
+            }
+            self.push_quoted(param);
+        }
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(std::mem::take(&mut self.current));
+        self.has_current = false;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.has_current || self.ifs.is_none() {
+            self.end_field();
+        }
+        self.fields
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // A single string
+    Word,
+    // A single string in pattern form
+    Pattern,
+    // Split into fields, in pattern form for globbing
+    Fields,
+    // Here-document body, which acts as if double quoted but has no closing
+    // quote and keeps the backslash in `\"`
+    HereDoc,
+}
+
+fn expand(shell: &mut Shell, word: &str, mode: Mode) -> Result<Vec<String>, String> {
+    let mut fields = Fields {
+        fields: Vec::new(),
+        current: String::new(),
+        has_current: false,
+        as_pattern: matches!(mode, Mode::Pattern | Mode::Fields),
+        ifs: (mode == Mode::Fields).then(|| shell.vars.get("IFS").unwrap_or(" \t\n").to_string()),
+    };
+    let heredoc = mode == Mode::HereDoc;
+    let mut quote_char = heredoc.then_some('"');
+    let mut chars = word.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quote_char) {
+            // Backslash outside quotes
+            ('\\', None) => match chars.next() {
+                Some('\n') => {}
+                Some(next_char) => fields.push_quoted(&next_char.to_string()),
+                None => fields.push_literal('\\'),
+            },
+            // Backslash inside double quotes
+            ('\\', Some('"')) => {
+                if let Some(next_char) = chars.next() {
+                    match next_char {
+                        '\n' => {}
+                        '"' if heredoc => fields.push_quoted("\\\""),
+                        '\\' | '$' | '"' | '`' => fields.push_quoted(&next_char.to_string()),
+                        _ => fields.push_quoted(&format!("\\{}", next_char)),
+                    }
+                }
+            }
+            ('$', Some('"')) if mode == Mode::Fields && chars.peek() == Some(&'@') => {
+                chars.next();
+                fields.push_params(&shell.positional);
+            }
+            // Expansions happen everywhere except inside single quotes
+            ('$' | '`', None | Some('"')) => {
+                let value = if c == '`' {
+                    let source = read_backquoted(&mut chars);
+                    Some(command_substitution(shell, &source)?)
+                } else {
+                    expand_dollar(shell, &mut chars)?
+                };
+                match value {
+                    Some(value) if quote_char.is_some() => fields.push_quoted(&value),
+                    Some(value) => fields.push_expanded(&value),
+                    None => fields.push_literal('$'),
+                }
+            }
+            // Quote handling
+            ('\'' | '"', None) => {
+                quote_char = Some(c);
+                fields.has_current = true;
+            }
+            ('"', Some('"')) if !heredoc => quote_char = None,
+            ('\'', Some('\'')) => quote_char = None,
+            // All other characters
+            (c, None) => fields.push_literal(c),
+            (c, Some(_)) => fields.push_quoted(&c.to_string()),
+        }
+    }
+
+    Ok(fields.finish())
+}
+
+// Expand what follows a `$`, `None` when it does not start an expansion
+fn expand_dollar(shell: &mut Shell, chars: &mut Peekable<Chars>) -> Result<Option<String>, String> {
+    let value = match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let content = read_braced(chars);
+            braced_parameter(shell, &content)?
+        }
+        Some('(') => {
+            chars.next();
+            match read_arithmetic(chars) {
+                Some(expr) => {
+                    let expr = expand(shell, &expr, Mode::Word)?.concat();
+                    arithmetic::evaluate(shell, &expr)?.to_string()
+                }
+                None => command_substitution(shell, &read_parenthesized(chars))?,
+            }
+        }
+        Some(&c) if "?$#@*".contains(c) || c.is_ascii_digit() => {
+            let name = chars.next().unwrap_or_default().to_string();
+            lookup(shell, &name).unwrap_or_default()
+        }
+        Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            lookup(shell, &name).unwrap_or_default()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+// Expression of a `$((...))`, leaving `chars` alone when the parentheses
+// do not close with `))` and it is a command substitution instead
+fn read_arithmetic(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.peek() != Some(&'(') {
+        return None;
+    }
+    let mut lookahead = chars.clone();
+    lookahead.next();
+    let expr = read_parenthesized(&mut lookahead);
+    if lookahead.next() != Some(')') {
+        return None;
+    }
+    *chars = lookahead;
+    Some(expr)
+}
+
+// Text up to the `)` closing a `$(`, skipping nested parentheses and quotes
+fn read_parenthesized(chars: &mut Peekable<Chars>) -> String {
+    let mut content = String::new();
+    let mut depth = 0;
+    let mut quote_char = None;
+    while let Some(c) = chars.next() {
+        match (c, quote_char) {
+            ('\\', Some('"') | None) => {
+                content.push(c);
+                if let Some(next_char) = chars.next() {
+                    content.push(next_char);
+                }
+                continue;
+            }
+            ('\'' | '"', None) => quote_char = Some(c),
+            ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
+            ('(', None) => depth += 1,
+            (')', None) if depth == 0 => break,
+            (')', None) => depth -= 1,
+            _ => {}
+        }
+        content.push(c);
+    }
+    content
+}
+
+// Body of a `` `...` ``, where `\$`, `` \` `` and `\\` lose their backslash
+fn read_backquoted(chars: &mut Peekable<Chars>) -> String {
+    let mut content = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => break,
+            '\\' => match chars.next() {
+                Some(next_char @ ('$' | '`' | '\\')) => content.push(next_char),
+                Some(next_char) => {
+                    content.push('\\');
+                    content.push(next_char);
+                }
+                None => content.push('\\'),
+            },
+            _ => content.push(c),
+        }
+    }
+    content
+}
+
+// Run `source` in a forked copy of the shell and capture its output, so
+// `cd`, assignments and `exit` inside it leave this shell alone
+fn command_substitution(shell: &mut Shell, source: &str) -> Result<String, String> {
+    let list = parser::parse(source).map_err(|error| error.to_string())?;
+
+    // The interactive shell leaves raw mode while it runs, so Ctrl-C
+    // interrupts it and what it writes to the terminal gets the usual line
+    // endings. The shell catches the SIGINT meant for it.
+    let terminal = shell.terminal.filter(|_| shell.job_control);
+    let mut raw_mode: libc::termios = unsafe { std::mem::zeroed() };
+    if let Some((fd, cooked)) = &terminal {
+        jobs::catch_interrupts(true);
+        unsafe {
+            libc::tcgetattr(*fd, &mut raw_mode);
+            libc::tcsetattr(*fd, libc::TCSANOW, cooked);
+        }
+    }
+    let captured = capture_output(shell, &list);
+    let mut interrupted = false;
+    if let Some((fd, _)) = &terminal {
+        unsafe { libc::tcsetattr(*fd, libc::TCSANOW, &raw_mode) };
+        interrupted = jobs::catch_interrupts(false);
+    }
+
+    let (output, status) = captured?;
+    shell.last_status = status;
+    shell.substitution_status = Some(status);
+    if interrupted || status == 128 + libc::SIGINT {
+        // The terminal only echoed `^C`, the command it is part of is dropped
+        if let Some((fd, _)) = terminal {
+            unsafe { libc::write(fd, b"\r\n".as_ptr() as *const libc::c_void, 2) };
+        }
+        shell.interrupted = true;
+        return Err(String::new());
+    }
+
+    let mut text = String::from_utf8_lossy(&output).into_owned();
+    while text.ends_with('\n') {
+        text.pop();
+    }
+    Ok(text)
+}
+
+// Output and exit status of `list` run in a forked copy of the shell
+fn capture_output(shell: &mut Shell, list: &List) -> Result<(Vec<u8>, i32), String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    let [read_fd, write_fd] = fds;
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            Err(io::Error::last_os_error().to_string())
+        }
+        0 => {
+            unsafe {
+                libc::signal(libc::SIGINT, libc::SIG_DFL);
+                libc::close(read_fd);
+                libc::dup2(write_fd, 1);
+                libc::close(write_fd);
+            }
+            shell.job_control = false;
+            jobs::install_sigchld_handler();
+            let mut stdout = io::stdout();
+            let status = execute_list(shell, list, &mut stdout).unwrap_or(1);
+            let _ = stdout.flush();
+            unsafe { libc::_exit(shell.exit_requested.unwrap_or(status)) }
+        }
+        pid => {
+            unsafe { libc::close(write_fd) };
+            let mut output = Vec::new();
+            let _ = unsafe { File::from_raw_fd(read_fd) }.read_to_end(&mut output);
+            let mut status = 0;
+            // Ctrl-C may interrupt the wait
+            while unsafe { libc::waitpid(pid, &mut status, 0) } == -1
+                && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+            {}
+            Ok((output, exit_code(ExitStatus::from_raw(status))))
+        }
+    }
+}
+
+// Text up to the `}` closing a `${`, skipping nested braces and quotes
+fn read_braced(chars: &mut Peekable<Chars>) -> String {
+    let mut content = String::new();
+    let mut depth = 0;
+    let mut quote_char = None;
+    while let Some(c) = chars.next() {
+        match (c, quote_char) {
+            ('\\', _) => {
+                content.push(c);
+                if let Some(next_char) = chars.next() {
+                    content.push(next_char);
+                }
+                continue;
+            }
+            ('\'' | '"', None) => quote_char = Some(c),
+            ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
+            ('{', None) => depth += 1,
+            ('}', None) if depth == 0 => break,
+            ('}', None) => depth -= 1,
+            _ => {}
+        }
+        content.push(c);
+    }
+    content
+}
+
+// Value of a named or special parameter, `None` when unset
+fn lookup(shell: &Shell, name: &str) -> Option<String> {
+    match name {
+        "?" => Some(shell.last_status.to_string()),
+        "$" => Some(shell.pid.to_string()),
+        "PIPESTATUS" => shell.pipestatus.first().map(|status| status.to_string()),
+        "#" => Some(shell.positional.len().to_string()),
+        "@" => Some(shell.positional.join(" ")),
+        // Joined with the first character of IFS
+        "*" => {
+            let ifs = shell.vars.get("IFS").unwrap_or(" ");
+            let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+            Some(shell.positional.join(&separator))
+        }
+        "0" => std::env::args().next(),
+        _ if name.chars().all(|c| c.is_ascii_digit()) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| shell.positional.get(n.checked_sub(1)?).cloned()),
+        _ => shell.vars.get(name).map(|value| value.to_string()),
+    }
+}
+
+// Elements of an array. `PIPESTATUS` is the only one, any other variable
+// acts as an array of its value.
+fn array_values(shell: &Shell, name: &str) -> Vec<String> {
+    match name {
+        "PIPESTATUS" => shell
+            .pipestatus
+            .iter()
+            .map(|status| status.to_string())
+            .collect(),
+        _ => lookup(shell, name).into_iter().collect(),
+    }
+}
+
+// `${name[i]}`, `${name[@]}` and their `${#...}` lengths, `None` when there
+// is no subscript
+fn subscripted(shell: &mut Shell, content: &str) -> Result<Option<String>, String> {
+    let (length, content) = match content.strip_prefix('#') {
+        Some(content) => (true, content),
+        None => (false, content),
+    };
+    let Some((name, subscript)) = content
+        .strip_suffix(']')
+        .and_then(|content| content.split_once('['))
+    else {
+        return Ok(None);
+    };
+    if !is_valid_name(name) {
+        return Ok(None);
+    }
+    let values = array_values(shell, name);
+    let all = matches!(subscript, "@" | "*");
+    let selected = if all {
+        values
+    } else {
+        // Negative indices count from the end
+        let index = arithmetic::evaluate(shell, subscript)?;
+        let index = if index < 0 {
+            values.len() as i64 + index
+        } else {
+            index
+        };
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| values.get(index).cloned())
+            .into_iter()
+            .collect()
+    };
+    Ok(Some(match (length, all) {
+        (true, true) => selected.len().to_string(),
+        (true, false) => selected.concat().chars().count().to_string(),
+        (false, _) => selected.join(" "),
+    }))
+}
+
+// Split `${name...}` content into the parameter name and the operator part
+fn split_name(content: &str) -> (&str, &str) {
+    let end = match content.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => content
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(content.len()),
+        Some(c) if c.is_ascii_digit() => content
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(content.len()),
+        Some('?' | '$' | '#' | '@' | '*' | '!' | '-') => 1,
+        _ => 0,
+    };
+    content.split_at(end)
+}
+
+// `${...}` forms: default values, pattern removal, length, substrings and
+// replacement
+fn braced_parameter(shell: &mut Shell, content: &str) -> Result<String, String> {
+    let bad_substitution = || format!("${{{}}}: bad substitution", content);
+    if let Some(value) = subscripted(shell, content)? {
+        return Ok(value);
+    }
+
+    // `${#name}` is the length of the value
+    if let Some(name) = content.strip_prefix('#') {
+        if !name.is_empty() && split_name(name) == (name, "") {
+            let value = lookup(shell, name).unwrap_or_default();
+            return Ok(value.chars().count().to_string());
+        }
+    }
+
+    let (name, rest) = split_name(content);
+    if name.is_empty() {
+        return Err(bad_substitution());
+    }
+    let value = lookup(shell, name);
+    if rest.is_empty() {
+        return Ok(value.unwrap_or_default());
+    }
+
+    // A leading `:` makes the default forms treat an empty value as unset
+    let (check_null, op) = match rest.strip_prefix(':') {
+        Some(op) if op.starts_with(['-', '=', '?', '+']) => (true, op),
+        Some(range) => return substring(shell, &value.unwrap_or_default(), range),
+        None => (false, rest),
+    };
+    let missing = match &value {
+        None => true,
+        Some(value) => check_null && value.is_empty(),
+    };
+    let operand = op.get(1..).unwrap_or_default();
+
+    match op.chars().next() {
+        Some('-') if missing => expand_word(shell, operand),
+        Some('=') if missing => {
+            if !is_valid_name(name) {
+                return Err(format!("${}: cannot assign in this way", name));
+            }
+            let word = expand_word(shell, operand)?;
+            shell.vars.set(name, word.clone())?;
+            Ok(word)
+        }
+        Some('?') if missing => {
+            let message = if operand.is_empty() {
+                "parameter null or not set".to_string()
+            } else {
+                expand_word(shell, operand)?
+            };
+            Err(format!("{}: {}", name, message))
+        }
+        Some('+') if missing => Ok(String::new()),
+        Some('+') => expand_word(shell, operand),
+        Some('-' | '=' | '?') => Ok(value.unwrap_or_default()),
+        Some('#') => {
+            let (longest, operand) = match operand.strip_prefix('#') {
+                Some(operand) => (true, operand),
+                None => (false, operand),
+            };
+            let pattern = expand_pattern(shell, operand)?;
+            Ok(remove_prefix(&value.unwrap_or_default(), &pattern, longest))
+        }
+        Some('%') => {
+            let (longest, operand) = match operand.strip_prefix('%') {
+                Some(operand) => (true, operand),
+                None => (false, operand),
+            };
+            let pattern = expand_pattern(shell, operand)?;
+            Ok(remove_suffix(&value.unwrap_or_default(), &pattern, longest))
+        }
+        Some('/') => {
+            let (mode, operand) = match operand.chars().next() {
+                Some(c @ ('/' | '#' | '%')) => (Some(c), &operand[1..]),
+                _ => (None, operand),
+            };
+            let (pattern, replacement) = split_replacement(operand);
+            let pattern = expand_pattern(shell, pattern)?;
+            let replacement = expand_word(shell, replacement)?;
+            Ok(replace(
+                &value.unwrap_or_default(),
+                &pattern,
+                &replacement,
+                mode,
+            ))
+        }
+        _ => Err(bad_substitution()),
+    }
+}
+
+// `${name:offset}` and `${name:offset:length}`, both arithmetic expressions.
+// Negative values count from the end.
+fn substring(shell: &mut Shell, value: &str, range: &str) -> Result<String, String> {
+    let chars: Vec<char> = value.chars().collect();
+    let len = chars.len() as i64;
+    let (offset, length) = match range.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (range, None),
+    };
+    let mut evaluate = |text: &str| {
+        let expr = expand(shell, text, Mode::Word)?.concat();
+        arithmetic::evaluate(shell, &expr)
+    };
+
+    let mut start = evaluate(offset)?;
+    if start < 0 {
+        start += len;
+    }
+    let start = start.clamp(0, len);
+    let end = match length {
+        None => len,
+        Some(length) => {
+            let length = evaluate(length)?;
+            if length < 0 {
+                len + length
+            } else {
+                start + length
+            }
+        }
+    };
+    if end < start {
+        return Err(format!("{}: substring expression < 0", range));
+    }
+    let end = end.min(len);
+    Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut ends: Vec<usize> = (0..=chars.len()).collect();
+    if longest {
+        ends.reverse();
+    }
+    for end in ends {
+        let prefix: String = chars[..end].iter().collect();
+        if pattern::matches(pattern, &prefix) {
+            return chars[end..].iter().collect();
+        }
+    }
+    value.to_string()
+}
+
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut starts: Vec<usize> = (0..=chars.len()).rev().collect();
+    if longest {
+        starts.reverse();
+    }
+    for start in starts {
+        let suffix: String = chars[start..].iter().collect();
+        if pattern::matches(pattern, &suffix) {
+            return chars[..start].iter().collect();
+        }
+    }
+    value.to_string()
+}
+
+// Split `pattern/replacement` at the first unescaped, unquoted `/`
+fn split_replacement(operand: &str) -> (&str, &str) {
+    let mut quote_char = None;
+    let mut escaped = false;
+    for (i, c) in operand.char_indices() {
+        match (c, quote_char) {
+            _ if escaped => escaped = false,
+            ('\\', _) => escaped = true,
+            ('\'' | '"', None) => quote_char = Some(c),
+            ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
+            ('/', None) => return (&operand[..i], &operand[i + 1..]),
+            _ => {}
+        }
+    }
+    (operand, "")
+}
+
+// `${name/pattern/replacement}`: `mode` is `/` to replace every match, `#`
+// and `%` to anchor the match at the start or end
+fn replace(value: &str, pattern: &str, replacement: &str, mode: Option<char>) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if pattern.is_empty() {
+        return value.to_string();
+    }
+    let matches_at = |start: usize, end: usize| {
+        let text: String = chars[start..end].iter().collect();
+        pattern::matches(pattern, &text)
+    };
+    // Longest match starting at `start`
+    let longest_from = |start: usize| {
+        (start..=chars.len())
+            .rev()
+            .find(|&end| matches_at(start, end))
+    };
+
+    let mut result = String::new();
+    let mut i = 0;
+    match mode {
+        Some('#') => {
+            if let Some(end) = longest_from(0) {
+                result.push_str(replacement);
+                i = end;
+            }
+        }
+        Some('%') => {
+            if let Some(start) = (0..=chars.len()).find(|&start| matches_at(start, chars.len())) {
+                let head: String = chars[..start].iter().collect();
+                return head + replacement;
+            }
+        }
+        _ => {
+            while i < chars.len() {
+                match longest_from(i).filter(|&end| end > i) {
+                    Some(end) => {
+                        result.push_str(replacement);
+                        i = end;
+                        if mode != Some('/') {
+                            break;
+                        }
+                    }
+                    None => {
+                        result.push(chars[i]);
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+    result.extend(&chars[i..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_with(vars: &[(&str, &str)], word: &str) -> Result<String, String> {
+        let mut shell = Shell::new();
+        for (name, value) in vars {
+            shell.vars.set(name, value.to_string()).unwrap();
+        }
+        expand_word(&mut shell, word)
+    }
+
+    #[test]
+    fn default_values() {
+        assert_eq!(expand_with(&[], "${NOPE:-a b}").unwrap(), "a b");
+        assert_eq!(expand_with(&[("E", "")], "${E-x}").unwrap(), "");
+        assert_eq!(expand_with(&[("E", "")], "${E:-x}").unwrap(), "x");
+        assert_eq!(expand_with(&[("V", "v")], "${V:+set}").unwrap(), "set");
+        assert_eq!(
+            expand_with(&[], "${NOPE:?missing}"),
+            Err("NOPE: missing".to_string())
+        );
+
+        let mut shell = Shell::new();
+        assert_eq!(expand_word(&mut shell, "${NEW:=made}").unwrap(), "made");
+        assert_eq!(shell.vars.get("NEW"), Some("made"));
+    }
+
+    #[test]
+    fn array_subscripts() {
+        let mut shell = Shell::new();
+        shell.pipestatus = vec![0, 1, 141];
+        let mut word = |text: &str| expand_word(&mut shell, text).unwrap();
+        assert_eq!(word("${PIPESTATUS[@]}"), "0 1 141");
+        assert_eq!(word("${PIPESTATUS[1+1]} ${PIPESTATUS[-3]}"), "141 0");
+        assert_eq!(word("${#PIPESTATUS[*]} ${#PIPESTATUS[2]}"), "3 3");
+        assert_eq!(word("$PIPESTATUS ${PIPESTATUS[5]}."), "0 .");
+        assert_eq!(
+            expand_with(&[("V", "abc")], "${V[0]} ${V[@]} ${V[1]}.").unwrap(),
+            "abc abc ."
+        );
+    }
+
+    #[test]
+    fn patterns_and_substrings() {
+        let vars = [("F", "archive.tar.gz")];
+        assert_eq!(expand_with(&vars, "${F#*.}").unwrap(), "tar.gz");
+        assert_eq!(expand_with(&vars, "${F##*.}").unwrap(), "gz");
+        assert_eq!(expand_with(&vars, "${F%.*}").unwrap(), "archive.tar");
+        assert_eq!(expand_with(&vars, "${F%%.*}").unwrap(), "archive");
+        assert_eq!(expand_with(&vars, "${#F}").unwrap(), "14");
+        assert_eq!(expand_with(&vars, "${F:1:3}").unwrap(), "rch");
+        assert_eq!(expand_with(&vars, "${F: -2}").unwrap(), "gz");
+        let vars = [("F", "archive.tar.gz"), ("N", "8")];
+        assert_eq!(
+            expand_with(&vars, "${F:1+1:N-6} ${F:$N} ${F:N:2*2}").unwrap(),
+            "ch tar.gz tar."
+        );
+        assert_eq!(expand_with(&vars, "${F/a/A}").unwrap(), "Archive.tar.gz");
+        assert_eq!(expand_with(&vars, "${F//a/A}").unwrap(), "Archive.tAr.gz");
+        assert_eq!(expand_with(&vars, "${F/%gz/xz}").unwrap(), "archive.tar.xz");
+        assert_eq!(expand_with(&vars, "${F#\"*.\"}").unwrap(), "archive.tar.gz");
+    }
+
+    #[test]
+    fn field_splitting() {
+        let mut shell = Shell::new();
+        shell.vars.set("V", " a  b ".to_string()).unwrap();
+        shell.vars.set("E", String::new()).unwrap();
+        let words: Vec<String> = ["x$V", "\"$V\"", "$E", "\"$E\""]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            expand_words(&mut shell, &words).unwrap(),
+            vec!["x", "a", "b", " a  b ", ""]
+        );
+
+        shell.vars.set("IFS", ":".to_string()).unwrap();
+        shell.vars.set("V", "a::b".to_string()).unwrap();
+        assert_eq!(
+            expand_words(&mut shell, &["$V".to_string()]).unwrap(),
+            vec!["a", "", "b"]
+        );
+    }
+
+    #[test]
+    fn shell_pid_in_subshells() {
+        let mut shell = Shell::new();
+        shell.pid = 1;
+        assert_eq!(expand_word(&mut shell, "$$ $(echo $$)").unwrap(), "1 1");
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let mut shell = Shell::new();
+        shell.positional = vec!["a b".to_string(), "c".to_string()];
+        let words: Vec<String> = ["\"$@\"", "$#", "x$2", "\"$*\"", "$@", "${3-none}"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            expand_words(&mut shell, &words).unwrap(),
+            vec!["a b", "c", "2", "xc", "a b c", "a", "b", "c", "none"]
+        );
+        shell.positional.clear();
+        assert_eq!(
+            expand_words(&mut shell, &["\"$@\"".to_string(), "\"x$@\"".to_string()]).unwrap(),
+            vec!["x"]
+        );
+    }
+
+    #[test]
+    fn tilde_prefixes() {
+        let mut shell = Shell::new();
+        shell.vars.set("HOME", "/home/me".to_string()).unwrap();
+        shell.vars.set("OLDPWD", "/old".to_string()).unwrap();
+        let words: Vec<String> = ["~", "~/a", "\"~\"", "~-/b", "x~", "A=~/c"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            expand_words(&mut shell, &words).unwrap(),
+            vec![
+                "/home/me",
+                "/home/me/a",
+                "~",
+                "/old/b",
+                "x~",
+                "A=/home/me/c"
+            ]
+        );
+        assert_eq!(
+            expand_assignment(&mut shell, "~/x:~/y:'~'").unwrap(),
+            "/home/me/x:/home/me/y:~"
+        );
+        assert_eq!(
+            expand_word(&mut shell, "~/x:~/y").unwrap(),
+            "/home/me/x:~/y"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+// Pathname expansion: matches a pattern against the filesystem one
+// `/`-separated component at a time.
+use crate::pattern;
+use std::fs;
+use std::path::Path;
+
+// Sorted paths matching the pattern, empty when nothing matches
+pub fn expand(pattern: &str) -> Vec<String> {
+    let mut paths = vec![String::new()];
+    for (i, component) in pattern.split('/').enumerate() {
+        let mut matched = Vec::new();
+        for path in &paths {
+            let prefix = if i == 0 {
+                String::new()
+            } else {
+                format!("{}/", path)
+            };
+            if !pattern::has_wildcards(component) {
+                matched.push(prefix + &pattern::unescape(component));
+                continue;
+            }
+            let dir = if prefix.is_empty() { "." } else { &prefix };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Hidden files only match a pattern starting with a literal dot
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if pattern::matches(component, &name) {
+                    matched.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+        paths = matched;
+    }
+    // Literal components were taken on trust, keep the paths that exist
+    paths.retain(|path| Path::new(path).symlink_metadata().is_ok());
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_files() {
+        let dir = std::env::temp_dir().join(format!("glob-test-{}", std::process::id()));
+        for file in ["a.rs", "b.rs", "c.txt", ".hidden.rs", "sub/d.rs"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let root = dir.to_string_lossy();
+        let relative = |paths: Vec<String>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| path[root.len() + 1..].to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            relative(expand(&format!("{}/*.rs", root))),
+            ["a.rs", "b.rs"]
+        );
+        assert_eq!(relative(expand(&format!("{}/.*.rs", root))), [".hidden.rs"]);
+        assert_eq!(relative(expand(&format!("{}/*/d.rs", root))), ["sub/d.rs"]);
+        assert_eq!(
+            relative(expand(&format!("{}/[ab].rs", root))),
+            ["a.rs", "b.rs"]
+        );
+        assert_eq!(relative(expand(&format!("{}/s*/", root))), ["sub/"]);
+        assert!(expand(&format!("{}/*.md", root)).is_empty());
+        assert!(expand(&format!("{}/\\*.rs", root)).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+// Job control: pipelines started by the shell, kept in a table while they
+// run in the background or are stopped.
+use libc::pid_t;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+// Set by the SIGCHLD handler, the table is checked before the next prompt
+static CHILD_CHANGED: AtomicBool = AtomicBool::new(false);
+
+// Self-pipe the SIGCHLD handler writes a byte to, so a wait can block until
+// some child changes state without missing one that already did
+static SIGCHLD_READ: AtomicI32 = AtomicI32::new(-1);
+static SIGCHLD_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    CHILD_CHANGED.store(true, Ordering::SeqCst);
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = 0u8;
+        libc::write(
+            SIGCHLD_WRITE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+// Also called in forked copies of the shell, which need a pipe of their own
+pub fn install_sigchld_handler() {
+    unsafe {
+        let mut fds = [-1; 2];
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) == -1 {
+            return;
+        }
+        for (fd, old) in [(fds[0], &SIGCHLD_READ), (fds[1], &SIGCHLD_WRITE)] {
+            let old = old.swap(fd, Ordering::SeqCst);
+            if old != -1 {
+                libc::close(old);
+            }
+        }
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigchld as *const () as libc::sighandler_t;
+        // Reading keys at the prompt must not fail with EINTR
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut());
+    }
+}
+
+// Block until a child changes state, returning at once if one did since
+// the last call
+pub fn wait_for_sigchld() {
+    let fd = SIGCHLD_READ.load(Ordering::SeqCst);
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Without the handler there is nothing to wait on, check again shortly
+    let timeout = if fd == -1 { 10 } else { -1 };
+    unsafe {
+        libc::poll(&mut poll_fd, 1, timeout);
+        let mut buffer = [0u8; 64];
+        while libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) > 0 {}
+    }
+}
+
+// Set when SIGINT arrives while it is caught
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Catch SIGINT, without restarting system calls, while the shell itself is
+// in the foreground; otherwise it would kill the shell. Returns whether one
+// arrived since it was turned on.
+pub fn catch_interrupts(catch: bool) -> bool {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = if catch {
+            on_sigint as *const () as libc::sighandler_t
+        } else {
+            libc::SIG_DFL
+        };
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Stopped,
+    Exited(i32),
+    // Killed by the signal
+    Signaled(i32),
+}
+
+impl State {
+    pub fn is_done(&self) -> bool {
+        matches!(self, State::Exited(_) | State::Signaled(_))
+    }
+
+    // Exit status as `$?` shows it
+    pub fn status(&self) -> i32 {
+        match self {
+            State::Exited(code) => *code,
+            State::Signaled(signal) => 128 + signal,
+            State::Stopped => 128 + libc::SIGTSTP,
+            State::Running => 0,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            State::Running => "Running".to_string(),
+            State::Stopped => "Stopped".to_string(),
+            State::Exited(0) => "Done".to_string(),
+            State::Exited(code) => format!("Exit {}", code),
+            State::Signaled(signal) => unsafe {
+                CStr::from_ptr(libc::strsignal(*signal))
+                    .to_string_lossy()
+                    .into_owned()
+            },
+        }
+    }
+}
+
+struct Process {
+    pid: pid_t,
+    state: State,
+}
+
+pub struct Job {
+    pub id: usize,
+    pub pgid: pid_t,
+    processes: Vec<Process>,
+    pub command: String,
+    // Last state the user was told about
+    reported: State,
+}
+
+impl Job {
+    pub fn new(pgid: pid_t, command: String) -> Job {
+        Job {
+            id: 0,
+            pgid,
+            processes: vec![],
+            command,
+            reported: State::Running,
+        }
+    }
+
+    pub fn push(&mut self, pid: pid_t) {
+        self.processes.push(Process {
+            pid,
+            state: State::Running,
+        });
+    }
+
+    // A command that never started, with the status it failed with
+    pub fn push_failed(&mut self, status: i32) {
+        self.processes.push(Process {
+            pid: 0,
+            state: State::Exited(status),
+        });
+    }
+
+    // Status of each process, as for `PIPESTATUS`
+    pub fn statuses(&self) -> Vec<i32> {
+        self.processes
+            .iter()
+            .map(|process| process.state.status())
+            .collect()
+    }
+
+    pub fn last_pid(&self) -> Option<pid_t> {
+        self.processes.last().map(|process| process.pid)
+    }
+
+    // Collect status changes of the processes without blocking
+    pub fn poll(&mut self) {
+        for process in &mut self.processes {
+            if process.state.is_done() {
+                continue;
+            }
+            let mut status = 0;
+            let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            match unsafe { libc::waitpid(process.pid, &mut status, flags) } {
+                0 => {}
+                // Reaped by someone else, nothing more to learn
+                -1 => process.state = State::Exited(0),
+                _ if libc::WIFEXITED(status) => {
+                    process.state = State::Exited(libc::WEXITSTATUS(status))
+                }
+                _ if libc::WIFSIGNALED(status) => {
+                    process.state = State::Signaled(libc::WTERMSIG(status))
+                }
+                _ if libc::WIFSTOPPED(status) => process.state = State::Stopped,
+                _ => process.state = State::Running,
+            }
+        }
+    }
+
+    // Running while any process runs, done once all of them are, with the
+    // state of the last one
+    pub fn state(&self) -> State {
+        let states = self.processes.iter().map(|process| process.state);
+        if states.clone().any(|state| state == State::Running) {
+            State::Running
+        } else if states.clone().any(|state| state == State::Stopped) {
+            State::Stopped
+        } else {
+            self.processes
+                .last()
+                .map_or(State::Exited(0), |process| process.state)
+        }
+    }
+
+    // Send SIGCONT to the whole process group
+    pub fn resume(&mut self) {
+        unsafe {
+            libc::kill(-self.pgid, libc::SIGCONT);
+        }
+        for process in &mut self.processes {
+            if process.state == State::Stopped {
+                process.state = State::Running;
+            }
+        }
+        self.reported = State::Running;
+    }
+
+    pub fn mark_reported(&mut self) {
+        self.reported = self.state();
+    }
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    // Job ids from least to most recently started, stopped or resumed; the
+    // last one is the current job (`%+`), the one before the previous (`%-`)
+    recent: Vec<usize>,
+}
+
+impl JobTable {
+    pub fn add(&mut self, mut job: Job) -> usize {
+        job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let id = job.id;
+        self.jobs.push(job);
+        self.touch(id);
+        id
+    }
+
+    // Make the job the current one
+    pub fn touch(&mut self, id: usize) {
+        self.recent.retain(|&recent| recent != id);
+        self.recent.push(id);
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        self.recent.retain(|&recent| recent != id);
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    // Job id from `%n`, `%+`/`%%`, `%-`, `%prefix` or `%?text`; no spec
+    // means the current job
+    pub fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let current = || self.recent.last().copied();
+        let found = match spec.map(|spec| spec.strip_prefix('%').unwrap_or(spec)) {
+            None | Some("") | Some("+") | Some("%") => {
+                return current().ok_or_else(|| "current: no such job".to_string())
+            }
+            Some("-") => self
+                .recent
+                .len()
+                .checked_sub(2)
+                .map(|i| self.recent[i])
+                .or_else(current),
+            Some(text) => match text.parse::<usize>() {
+                Ok(id) => self.jobs.iter().find(|job| job.id == id).map(|job| job.id),
+                Err(_) => {
+                    let matching: Vec<usize> = self
+                        .jobs
+                        .iter()
+                        .filter(|job| match text.strip_prefix('?') {
+                            Some(text) => job.command.contains(text),
+                            None => job.command.starts_with(text),
+                        })
+                        .map(|job| job.id)
+                        .collect();
+                    if matching.len() > 1 {
+                        return Err(format!("{}: ambiguous job spec", spec.unwrap()));
+                    }
+                    matching.first().copied()
+                }
+            },
+        };
+        found.ok_or_else(|| format!("{}: no such job", spec.unwrap_or("")))
+    }
+
+    // `+` for the current job, `-` for the previous one
+    pub fn mark(&self, id: usize) -> char {
+        if self.recent.last() == Some(&id) {
+            '+'
+        } else if self.recent.len() > 1 && self.recent[self.recent.len() - 2] == id {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    // `[1]+  Running                 sleep 10 &`
+    pub fn format(&self, job: &Job) -> String {
+        let mark = self.mark(job.id);
+        let state = job.state();
+        let suffix = if state == State::Running { " &" } else { "" };
+        format!(
+            "[{}]{}  {:<24}{}{}",
+            job.id,
+            mark,
+            state.describe(),
+            job.command,
+            suffix
+        )
+    }
+
+    // Every job for `jobs`. Finished jobs are shown one last time.
+    pub fn list(&mut self) -> Vec<String> {
+        self.poll();
+        let lines = self.jobs.iter().map(|job| self.format(job)).collect();
+        for job in &mut self.jobs {
+            job.mark_reported();
+        }
+        self.remove_done();
+        lines
+    }
+
+    // Jobs that finished or stopped since the user last heard of them,
+    // checked when SIGCHLD arrived
+    pub fn notifications(&mut self) -> Vec<String> {
+        if !CHILD_CHANGED.swap(false, Ordering::SeqCst) {
+            return vec![];
+        }
+        self.poll();
+        let mut lines = vec![];
+        for i in 0..self.jobs.len() {
+            let job = &self.jobs[i];
+            if job.state() != job.reported {
+                lines.push(self.format(job));
+                self.jobs[i].mark_reported();
+            }
+        }
+        self.remove_done();
+        lines
+    }
+
+    fn poll(&mut self) {
+        for job in &mut self.jobs {
+            job.poll();
+        }
+    }
+
+    fn remove_done(&mut self) {
+        let done: Vec<usize> = self
+            .jobs
+            .iter()
+            .filter(|job| job.state().is_done())
+            .map(|job| job.id)
+            .collect();
+        for id in done {
+            self.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(commands: &[&str]) -> JobTable {
+        let mut table = JobTable::default();
+        for command in commands {
+            table.add(Job::new(0, command.to_string()));
+        }
+        table
+    }
+
+    #[test]
+    fn job_specs() {
+        let mut jobs = table(&["sleep 10", "vim notes", "sleep 20"]);
+        assert_eq!(jobs.find(None), Ok(3));
+        assert_eq!(jobs.find(Some("%%")), Ok(3));
+        assert_eq!(jobs.find(Some("%-")), Ok(2));
+        assert_eq!(jobs.find(Some("%1")), Ok(1));
+        assert_eq!(jobs.find(Some("2")), Ok(2));
+        assert_eq!(jobs.find(Some("%vim")), Ok(2));
+        assert_eq!(jobs.find(Some("%?20")), Ok(3));
+        assert_eq!(
+            jobs.find(Some("%sleep")),
+            Err("%sleep: ambiguous job spec".to_string())
+        );
+        assert_eq!(jobs.find(Some("%4")), Err("%4: no such job".to_string()));
+
+        jobs.touch(1);
+        assert_eq!(jobs.find(None), Ok(1));
+        jobs.remove(1);
+        jobs.remove(3);
+        assert_eq!(jobs.find(Some("%-")), Ok(2));
+        jobs.remove(2);
+        assert_eq!(jobs.find(None), Err("current: no such job".to_string()));
+        // Numbers are reused once the table empties
+        assert_eq!(jobs.add(Job::new(0, "ls".to_string())), 1);
+    }
+
+    #[test]
+    fn job_lines() {
+        let mut jobs = table(&["sleep 10", "sleep 20"]);
+        let job = jobs.get_mut(1).unwrap();
+        job.push(1);
+        job.processes[0].state = State::Stopped;
+        let job = jobs.get_mut(2).unwrap();
+        job.push(2);
+        assert_eq!(
+            jobs.format(&jobs.jobs[0]),
+            "[1]-  Stopped                 sleep 10"
+        );
+        assert_eq!(
+            jobs.format(&jobs.jobs[1]),
+            "[2]+  Running                 sleep 20 &"
+        );
+        assert_eq!(State::Exited(1).describe(), "Exit 1");
+        assert_eq!(State::Signaled(libc::SIGTERM).describe(), "Terminated");
+    }
+}
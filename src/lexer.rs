@@ -0,0 +1,360 @@
+use crate::parser::ParseError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // Raw word text, quotes are left in place for the expansion phase
+    Word(String),
+    // Digits directly followed by a redirection operator (`2>`)
+    IoNumber(u32),
+    // Expression of a `((...))` command
+    Arithmetic(String),
+    // Here-document body, in place of the delimiter word after `<<`
+    HereDoc { body: String, expand: bool },
+    Op(Operator),
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Pipe,
+    PipeAmp,
+    OrIf,
+    AndIf,
+    Amp,
+    Semi,
+    DSemi,
+    LParen,
+    RParen,
+    Great,
+    DGreat,
+    DLess,
+    DLessDash,
+    TLess,
+    Less,
+    GreatAnd,
+    LessAnd,
+    AndGreat,
+    AndDGreat,
+    Clobber,
+}
+
+impl Operator {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Pipe => "|",
+            Operator::PipeAmp => "|&",
+            Operator::OrIf => "||",
+            Operator::AndIf => "&&",
+            Operator::Amp => "&",
+            Operator::Semi => ";",
+            Operator::DSemi => ";;",
+            Operator::LParen => "(",
+            Operator::RParen => ")",
+            Operator::Great => ">",
+            Operator::DGreat => ">>",
+            Operator::DLess => "<<",
+            Operator::DLessDash => "<<-",
+            Operator::TLess => "<<<",
+            Operator::Less => "<",
+            Operator::GreatAnd => ">&",
+            Operator::LessAnd => "<&",
+            Operator::AndGreat => "&>",
+            Operator::AndDGreat => "&>>",
+            Operator::Clobber => ">|",
+        }
+    }
+}
+
+impl Token {
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => word.clone(),
+            Token::IoNumber(n) => n.to_string(),
+            Token::Arithmetic(expr) => format!("(({}))", expr),
+            Token::HereDoc { body, .. } => body.clone(),
+            Token::Op(op) => op.as_str().to_string(),
+            Token::Newline => "newline".to_string(),
+        }
+    }
+}
+
+pub fn lex(input: &str) -> Result<Vec<Token>, ParseError> {
+    Lexer {
+        chars: input.chars().collect(),
+        pos: 0,
+        tokens: Vec::new(),
+        pending_heredocs: Vec::new(),
+    }
+    .run()
+}
+
+fn is_metachar(c: char) -> bool {
+    matches!(
+        c,
+        ' ' | '\t' | '\n' | '|' | '&' | ';' | '>' | '<' | '(' | ')'
+    )
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    tokens: Vec<Token>,
+    // Delimiter words whose bodies start after the next newline, as token
+    // indices paired with whether leading tabs are stripped (`<<-`)
+    pending_heredocs: Vec<(usize, bool)>,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn run(mut self) -> Result<Vec<Token>, ParseError> {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                // Line continuation between words
+                '\\' if self.peek_at(1) == Some('\n') => self.pos += 2,
+                '\n' => {
+                    self.pos += 1;
+                    self.tokens.push(Token::Newline);
+                    for (index, strip_tabs) in std::mem::take(&mut self.pending_heredocs) {
+                        self.heredoc_body(index, strip_tabs)?;
+                    }
+                }
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                '(' if self.peek_at(1) == Some('(') => self.arithmetic()?,
+                c if is_metachar(c) => self.operator(c),
+                _ => self.word()?,
+            }
+        }
+        if let Some(&(index, _)) = self.pending_heredocs.first() {
+            let delimiter = self.tokens[index].describe();
+            return Err(ParseError::UnterminatedHereDoc(unquote(&delimiter)));
+        }
+        Ok(self.tokens)
+    }
+
+    fn operator(&mut self, c: char) {
+        let next = self.peek_at(1);
+        let (op, len) = match (c, next) {
+            ('|', Some('|')) => (Operator::OrIf, 2),
+            ('|', Some('&')) => (Operator::PipeAmp, 2),
+            ('|', _) => (Operator::Pipe, 1),
+            ('&', Some('&')) => (Operator::AndIf, 2),
+            ('&', Some('>')) if self.peek_at(2) == Some('>') => (Operator::AndDGreat, 3),
+            ('&', Some('>')) => (Operator::AndGreat, 2),
+            ('&', _) => (Operator::Amp, 1),
+            ('>', Some('>')) => (Operator::DGreat, 2),
+            ('>', Some('&')) => (Operator::GreatAnd, 2),
+            ('>', Some('|')) => (Operator::Clobber, 2),
+            ('>', _) => (Operator::Great, 1),
+            ('<', Some('<')) => match (self.peek_at(2), self.peek_at(3)) {
+                (Some('<'), _) => (Operator::TLess, 3),
+                (Some('-'), _) => (Operator::DLessDash, 3),
+                _ => (Operator::DLess, 2),
+            },
+            ('<', Some('&')) => (Operator::LessAnd, 2),
+            ('<', _) => (Operator::Less, 1),
+            ('(', _) => (Operator::LParen, 1),
+            (')', _) => (Operator::RParen, 1),
+            (';', Some(';')) => (Operator::DSemi, 2),
+            _ => (Operator::Semi, 1),
+        };
+        self.pos += len;
+        self.tokens.push(Token::Op(op));
+    }
+
+    fn word(&mut self) -> Result<(), ParseError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            match c {
+                c if is_metachar(c) => break,
+                '\\' => self.pos += 2,
+                '$' if self.peek_at(1) == Some('{') => self.braced_parameter()?,
+                '$' if self.peek_at(1) == Some('(') => self.command_substitution()?,
+                '`' => self.backquoted()?,
+                '\'' => {
+                    self.pos += 1;
+                    while self.peek() != Some('\'') {
+                        if self.peek().is_none() {
+                            return Err(ParseError::UnterminatedQuote('\''));
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                '"' => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            None => return Err(ParseError::UnterminatedQuote('"')),
+                            Some('\\') => self.pos += 2,
+                            Some('$') if self.peek_at(1) == Some('{') => self.braced_parameter()?,
+                            Some('$') if self.peek_at(1) == Some('(') => {
+                                self.command_substitution()?
+                            }
+                            Some('`') => self.backquoted()?,
+                            Some('"') => break,
+                            Some(_) => self.pos += 1,
+                        }
+                    }
+                    self.pos += 1;
+                }
+                _ => self.pos += 1,
+            }
+        }
+        self.pos = self.pos.min(self.chars.len());
+        let text: String = self.chars[start..self.pos].iter().collect();
+
+        if matches!(self.peek(), Some('>' | '<')) && text.chars().all(|c| c.is_ascii_digit()) {
+            if let Ok(fd) = text.parse() {
+                self.tokens.push(Token::IoNumber(fd));
+                return Ok(());
+            }
+        }
+        let strip_tabs = match self.tokens.last() {
+            Some(Token::Op(Operator::DLess)) => Some(false),
+            Some(Token::Op(Operator::DLessDash)) => Some(true),
+            _ => None,
+        };
+        if let Some(strip_tabs) = strip_tabs {
+            self.pending_heredocs.push((self.tokens.len(), strip_tabs));
+        }
+        self.tokens.push(Token::Word(text));
+        Ok(())
+    }
+
+    // Lines up to the delimiter, replacing the delimiter word at `index`
+    fn heredoc_body(&mut self, index: usize, strip_tabs: bool) -> Result<(), ParseError> {
+        let word = self.tokens[index].describe();
+        let delimiter = unquote(&word);
+        let mut body = String::new();
+        loop {
+            if self.pos >= self.chars.len() {
+                return Err(ParseError::UnterminatedHereDoc(delimiter));
+            }
+            let end = self.chars[self.pos..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(self.chars.len(), |len| self.pos + len);
+            let mut line: String = self.chars[self.pos..end].iter().collect();
+            self.pos = (end + 1).min(self.chars.len());
+            if strip_tabs {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            if line == delimiter {
+                break;
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+        self.tokens[index] = Token::HereDoc {
+            body,
+            expand: !word.contains(['\'', '"', '\\']),
+        };
+        Ok(())
+    }
+
+    // `((...))`, ending at the `))` that closes it
+    fn arithmetic(&mut self) -> Result<(), ParseError> {
+        self.pos += 2;
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                None => return Err(ParseError::UnterminatedQuote(')')),
+                Some('(') => depth += 1,
+                Some(')') if depth == 0 => {
+                    if self.peek_at(1) != Some(')') {
+                        return Err(ParseError::UnexpectedToken(")".to_string()));
+                    }
+                    break;
+                }
+                Some(')') => depth -= 1,
+                Some(_) => {}
+            }
+            self.pos += 1;
+        }
+        let expr = self.chars[start..self.pos].iter().collect();
+        self.pos += 2;
+        self.tokens.push(Token::Arithmetic(expr));
+        Ok(())
+    }
+
+    // `${...}`, which may contain quotes and metacharacters
+    fn braced_parameter(&mut self) -> Result<(), ParseError> {
+        self.pos += 2;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.peek() {
+                None => return Err(ParseError::UnterminatedQuote('}')),
+                Some('\\') => self.pos += 1,
+                Some('{') => depth += 1,
+                Some('}') => depth -= 1,
+                Some(_) => {}
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    // `$(...)`, skipping nested parentheses and quoted text
+    fn command_substitution(&mut self) -> Result<(), ParseError> {
+        self.pos += 2;
+        let mut depth = 1;
+        let mut quote_char = None;
+        while depth > 0 {
+            let c = self.peek().ok_or(ParseError::UnterminatedQuote(')'))?;
+            match (c, quote_char) {
+                ('\\', Some('"') | None) => self.pos += 1,
+                ('\'' | '"', None) => quote_char = Some(c),
+                ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
+                ('(', None) => depth += 1,
+                (')', None) => depth -= 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    // `` `...` ``, a backslash escapes the closing backquote
+    fn backquoted(&mut self) -> Result<(), ParseError> {
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None => return Err(ParseError::UnterminatedQuote('`')),
+                Some('\\') => self.pos += 2,
+                Some('`') => break,
+                Some(_) => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+        Ok(())
+    }
+}
+
+// Here-document delimiter with quotes and backslashes removed
+fn unquote(word: &str) -> String {
+    let mut text = String::new();
+    let mut quote_char = None;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote_char) {
+            ('\\', None) => text.extend(chars.next()),
+            ('\'' | '"', None) => quote_char = Some(c),
+            (c, Some(quote)) if c == quote => quote_char = None,
+            _ => text.push(c),
+        }
+    }
+    text
+}
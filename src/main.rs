@@ -1,115 +1,152 @@
+pub mod arithmetic;
+pub mod ast;
+pub mod brace;
 pub mod builtins;
+pub mod expand;
+pub mod glob;
+pub mod jobs;
+pub mod lexer;
+pub mod parser;
+pub mod pattern;
+pub mod redirect;
 pub mod shell;
 pub mod utils;
+pub mod variables;
 use crate::utils::*;
 use shell::*;
 use std::fs::OpenOptions;
 #[allow(unused_imports)]
 use std::io::{self, Write};
-use std::io::{stdin, stdout};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process;
-use termion::event::Event;
 use termion::event::Key;
-use utils::*;
-// , Key, MouseEvent};
-//
-// use termion::event::Key;
-use termion::input::MouseTerminal;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
-use std::io::prelude::*;
-
-enum Output {
-    AppendStdErr(Vec<String>),
-    AppendStdOut(Vec<String>),
-    RedirectStdOut(Vec<String>),
-    RedirectStdErr(Vec<String>),
-    StdOut,
-}
-
 fn main() -> io::Result<()> {
-    let mut stdout = io::stdout().into_raw_mode().unwrap();
+    // Stopping signals from the terminal are for the jobs, and SIGTTOU would
+    // stop the shell when it takes the terminal back
+    for signal in JOB_CONTROL_SIGNALS {
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+    }
+    jobs::install_sigchld_handler();
+    // The terminal on a descriptor of its own, out of the way of the ones
+    // the shell redirects for commands it runs itself
+    let terminal = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let terminal = redirect::move_to_private_fd(terminal)?;
+    let mut cooked: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe { libc::tcgetattr(terminal.as_raw_fd(), &mut cooked) };
+    let mut stdout = terminal.into_raw_mode()?;
     let mut shell = Shell::new();
+    shell.job_control = true;
+    shell.terminal = Some((stdout.as_raw_fd(), cooked));
     let _ = std::fs::write(".history", "");
-    loop {
-        write!(stdout, "\r{}$ ", termion::clear::CurrentLine)?;
-        io::stdout().flush()?;
+    write!(stdout, "\r{}$ ", termion::clear::CurrentLine)?;
+    io::stdout().flush()?;
 
-        let stdin = io::stdin();
-        // Read character by character
-        for key in stdin.keys() {
-            match key.unwrap() {
-                Key::Char('\t') => {
-                    let completions = shell.get_completions();
+    let stdin = io::stdin();
+    // Read character by character
+    for key in stdin.keys() {
+        match key.unwrap() {
+            Key::Char('\t') => {
+                let completions = shell.get_completions();
 
-                    if completions.is_empty() {
-                        write!(stdout, "\r\x07")?;
-                        stdout.flush()?;
-                    } else if completions.len() == 1 {
-                        // Single completion - auto-complete
-                        shell.input = completions[0].clone() + " ";
-                        shell.cursor_pos = shell.input.len();
-                        shell.redraw_line(&mut stdout)?;
-                    } else if shell.last_key_was_tab {
-                        // Multiple completions - show them
-                        write!(stdout, "\r\n")?;
-                        for completion in &completions {
-                            write!(stdout, "{}  ", completion)?;
-                        }
-                        write!(stdout, "\r\n")?;
+                if completions.is_empty() {
+                    write!(stdout, "\r\x07")?;
+                    stdout.flush()?;
+                } else if completions.len() == 1 {
+                    // Single completion - auto-complete
+                    shell.input = completions[0].clone() + " ";
+                    shell.cursor_pos = shell.input.len();
+                    shell.redraw_line(&mut stdout)?;
+                } else if shell.last_key_was_tab {
+                    // Multiple completions - show them
+                    write!(stdout, "\r\n")?;
+                    for completion in &completions {
+                        write!(stdout, "{}  ", completion)?;
+                    }
+                    write!(stdout, "\r\n")?;
+                    shell.redraw_line(&mut stdout)?;
+                } else {
+                    // First tab - complete common prefix or ring bell
+                    let common = shell.find_common_prefix(&completions);
+                    if common.len() > shell.cursor_pos {
+                        shell.input = common;
+                        shell.cursor_pos = shell.input.chars().count();
                         shell.redraw_line(&mut stdout)?;
                     } else {
-                        // First tab - complete common prefix or ring bell
-                        let common = shell.find_common_prefix(&completions);
-                        if common.len() > shell.cursor_pos {
-                            shell.input = common;
-                            shell.cursor_pos = shell.input.chars().count();
-                            shell.redraw_line(&mut stdout)?;
-                        } else {
-                            write!(stdout, "\x07")?;
-                            stdout.flush()?;
-                        }
+                        write!(stdout, "\x07")?;
+                        stdout.flush()?;
                     }
-                    shell.last_key_was_tab = true; // ← Mark that tab was pressed
                 }
-                Key::Char('\n') => {
-                    shell.last_key_was_tab = false;
-                    write!(stdout, "\r\n")?;
+                shell.last_key_was_tab = true; // ← Mark that tab was pressed
+            }
+            Key::Char('\n') => {
+                shell.last_key_was_tab = false;
+                write!(stdout, "\r\n")?;
+                io::stdout().flush()?;
+
+                let source = format!("{}{}", shell.continuation, shell.input);
+                let parsed = parser::parse(&source);
+                shell.input.clear();
+                shell.cursor_pos = 0;
+                if matches!(&parsed, Err(error) if error.is_incomplete()) {
+                    // Keep reading lines until the command is complete
+                    shell.continuation = source + "\n";
+                    write!(stdout, "\r{}> ", termion::clear::CurrentLine)?;
                     io::stdout().flush()?;
+                    continue;
+                }
+                shell.continuation.clear();
 
-                    let input = shell.input.trim().to_string();
-                    shell.add_to_history(input.clone());
+                let input = source.trim().to_string();
+                shell.add_to_history(input.clone());
 
-                    append_to_file(Path::new(".history"), &input)?;
-                    // Check for exit first
-                    if input.starts_with("exit") {
-                        let code = input
-                            .split_whitespace()
-                            .nth(1)
-                            .and_then(|s| s.parse::<i32>().ok())
-                            .unwrap_or(0);
-                        drop(stdout);
-                        io::stdout().flush()?;
-                        process::exit(code);
+                append_to_file(Path::new(".history"), &input)?;
+                match parsed {
+                    Ok(list) => {
+                        shell.interrupted = false;
+                        execute_list(&mut shell, &list, &mut stdout)?;
                     }
-                    if shell.input.contains("|") {
-                        execute_pipeline_interruptible(&shell.input, &mut stdout)?;
-                    } else {
-                        execute_single_interruptible(&shell.input, &mut stdout)?;
+                    Err(error) => {
+                        write!(io::stderr(), "{}\r\n", error)?;
+                        shell.last_status = 2;
                     }
-                    // Reset for next command
-                    shell.input.clear();
-                    shell.cursor_pos = 0;
-                    // Good - clear the entire line first
-                    write!(stdout, "\r{}$ ", termion::clear::CurrentLine)?;
-                    io::stdout().flush().unwrap();
                 }
-                Key::Char(c) => {
-                    shell.last_key_was_tab = false;
+                if let Some(code) = shell.exit_requested {
+                    drop(stdout);
+                    io::stdout().flush()?;
+                    process::exit(code);
+                }
+                for line in shell.jobs.notifications() {
+                    write!(stdout, "{}\r\n", line)?;
+                }
+                // Good - clear the entire line first
+                write!(stdout, "\r{}$ ", termion::clear::CurrentLine)?;
+                io::stdout().flush().unwrap();
+            }
+            Key::Char(c) => {
+                shell.last_key_was_tab = false;
+                // Convert character position to byte position
+                //
+                let byte_pos = shell
+                    .input
+                    .char_indices()
+                    .nth(shell.cursor_pos)
+                    .map(|(pos, _)| pos)
+                    .unwrap_or(shell.input.len());
+
+                shell.input.insert(byte_pos, c);
+                shell.cursor_pos += 1;
+                shell.redraw_line(&mut stdout)?;
+            }
+            Key::Backspace => {
+                shell.last_key_was_tab = false;
+                if shell.cursor_pos > 0 {
+                    shell.cursor_pos -= 1;
+
                     // Convert character position to byte position
-                    //
                     let byte_pos = shell
                         .input
                         .char_indices()
@@ -117,55 +154,56 @@ fn main() -> io::Result<()> {
                         .map(|(pos, _)| pos)
                         .unwrap_or(shell.input.len());
 
-                    shell.input.insert(byte_pos, c);
-                    shell.cursor_pos += 1;
+                    shell.input.remove(byte_pos);
                     shell.redraw_line(&mut stdout)?;
                 }
-                Key::Backspace => {
-                    shell.last_key_was_tab = false;
-                    if shell.cursor_pos > 0 {
-                        shell.cursor_pos -= 1;
-
-                        // Convert character position to byte position
-                        let byte_pos = shell
-                            .input
-                            .char_indices()
-                            .nth(shell.cursor_pos)
-                            .map(|(pos, _)| pos)
-                            .unwrap_or(shell.input.len());
-
-                        shell.input.remove(byte_pos);
-                        shell.redraw_line(&mut stdout)?;
-                    }
-                }
-                Key::Up => {
-                    shell.history_prev();
-                    shell.redraw_line(&mut stdout)?;
-                }
-                Key::Down => {
-                    shell.history_next();
-                    shell.redraw_line(&mut stdout)?;
-                }
-                Key::Ctrl('c') => {
-                    shell.last_key_was_tab = false;
-                    // Exit on Ctrl+C
-                    write!(stdout, "\r\n").unwrap();
-                    stdout.flush().unwrap();
-                    drop(stdout); // Exit raw mode
-                    std::process::exit(0);
-                }
-                _ => {
-                    shell.last_key_was_tab = false;
-                }
+            }
+            Key::Up => {
+                shell.history_prev();
+                shell.redraw_line(&mut stdout)?;
+            }
+            Key::Down => {
+                shell.history_next();
+                shell.redraw_line(&mut stdout)?;
+            }
+            Key::Ctrl('d') if shell.input.is_empty() => {
+                // EOF at an empty prompt exits with the last status
+                write!(stdout, "\r\n")?;
+                break;
+            }
+            Key::Ctrl('c') => {
+                shell.last_key_was_tab = false;
+                // Discard the line, along with earlier lines of an unfinished command
+                write!(stdout, "^C\r\n")?;
+                shell.input.clear();
+                shell.cursor_pos = 0;
+                shell.continuation.clear();
+                shell.history_index = shell.history.len();
+                shell.last_status = 130;
+                shell.redraw_line(&mut stdout)?;
+            }
+            _ => {
+                shell.last_key_was_tab = false;
             }
         }
     }
+    drop(stdout);
+    io::stdout().flush()?;
+    process::exit(shell.last_status);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tokenize(input: &str) -> Vec<String> {
+        let list = parser::parse(input).unwrap();
+        let ast::Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        expand::expand_words(&mut Shell::new(), &command.words).unwrap()
+    }
+
     #[test]
     fn quoted_strings() {
         // Test with double quotes
@@ -180,7 +218,7 @@ mod tests {
         let s3 = "'hello world'  \"foo bar\"  'baz'";
         assert_eq!(tokenize(s3), vec!["hello world", "foo bar", "baz"]);
         let s4 = "before\\   after";
-        assert_eq!(tokenize(s4), vec!["before\\   after"]);
+        assert_eq!(tokenize(s4), vec!["before ", "after"]);
         let s5 = "world\\ \\ \\ \\ \\ \\ script";
         assert_eq!(tokenize(s5), vec!["world      script"]);
     }
@@ -0,0 +1,779 @@
+use crate::ast::*;
+use crate::lexer::*;
+use crate::variables::{is_valid_name, split_assignment};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken(String),
+    UnexpectedEof,
+    UnterminatedQuote(char),
+    // Input ended before the here-document delimiter
+    UnterminatedHereDoc(String),
+}
+
+impl ParseError {
+    // More lines could complete the input
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnexpectedEof | ParseError::UnterminatedHereDoc(_)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken(token) => {
+                write!(f, "syntax error near unexpected token `{}'", token)
+            }
+            ParseError::UnexpectedEof => write!(f, "syntax error: unexpected end of file"),
+            ParseError::UnterminatedQuote(quote) => {
+                write!(f, "unexpected EOF while looking for matching `{}'", quote)
+            }
+            ParseError::UnterminatedHereDoc(delimiter) => write!(
+                f,
+                "here-document delimited by end-of-file (wanted `{}')",
+                delimiter
+            ),
+        }
+    }
+}
+
+// Words that start or continue a compound command in command position
+pub const RESERVED_WORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "for", "do", "done", "case", "esac", "{",
+    "}", "function",
+];
+
+pub fn parse(input: &str) -> Result<List, ParseError> {
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        pos: 0,
+    };
+    let list = parser.list(&[])?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected());
+    }
+    Ok(list)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<Operator> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::UnexpectedToken(token.describe()),
+            None => ParseError::UnexpectedEof,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    // Reserved word at the current token, which must be in command position
+    fn peek_keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) if RESERVED_WORDS.contains(&word.as_str()) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(Token::Word(word)) if word == keyword => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // list := and_or ((';' | '&' | NEWLINE) and_or)* [';' | '&']
+    //
+    // Ends before any of the `end` keywords, or `;;` in a case item
+    fn list(&mut self, end: &[&str]) -> Result<List, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            let at_end = self
+                .peek_keyword()
+                .is_some_and(|keyword| end.contains(&keyword));
+            if self.peek().is_none() || at_end || self.peek_op() == Some(Operator::DSemi) {
+                break;
+            }
+            let mut and_or = self.and_or()?;
+            match self.peek() {
+                None | Some(Token::Op(Operator::DSemi)) => {}
+                Some(Token::Newline) | Some(Token::Op(Operator::Semi)) => self.pos += 1,
+                Some(Token::Op(Operator::Amp)) => {
+                    and_or.background = true;
+                    self.pos += 1;
+                }
+                Some(_) => return Err(self.unexpected()),
+            }
+            items.push(and_or);
+        }
+        Ok(items)
+    }
+
+    // and_or := pipeline (('&&' | '||') linebreak pipeline)*
+    fn and_or(&mut self) -> Result<AndOrList, ParseError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek_op() {
+                Some(Operator::AndIf) => Connector::And,
+                Some(Operator::OrIf) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+        Ok(AndOrList {
+            first,
+            rest,
+            background: false,
+        })
+    }
+
+    // pipeline := command (('|' | '|&') linebreak command)*
+    fn pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut commands = vec![self.command()?];
+        while let Some(op @ (Operator::Pipe | Operator::PipeAmp)) = self.peek_op() {
+            // `a |& b` is `a 2>&1 | b`, after the command's own redirections.
+            // A function definition has no output.
+            let last = commands.len() - 1;
+            let redirects = match &mut commands[last] {
+                Command::Simple(simple) => Some(&mut simple.redirects),
+                Command::Compound(_, redirects) => Some(redirects),
+                Command::Function(..) => None,
+            };
+            if let (Operator::PipeAmp, Some(redirects)) = (op, redirects) {
+                redirects.push(Redirect {
+                    fd: Some(2),
+                    kind: RedirectKind::DupOutput,
+                    target: "1".to_string(),
+                });
+            }
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline { commands })
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        if let Some(Token::Arithmetic(expr)) = self.peek() {
+            let words = vec!["let".to_string(), arithmetic_word(expr)];
+            self.pos += 1;
+            let mut simple = self.simple_command()?;
+            // Only redirections may follow
+            let extra = simple
+                .assignments
+                .first()
+                .map(|a| format!("{}={}", a.name, a.value));
+            if let Some(word) = extra.or_else(|| simple.words.first().cloned()) {
+                return Err(ParseError::UnexpectedToken(word));
+            }
+            simple.words = words;
+            return Ok(Command::Simple(simple));
+        }
+        if let (Some(Token::Word(name)), Some(Token::Op(Operator::LParen))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            if is_valid_name(name) && self.peek_keyword().is_none() {
+                let name = name.clone();
+                self.pos += 2;
+                return self.function_body(name, true);
+            }
+        }
+        let compound = match self.peek_keyword() {
+            Some("{") => {
+                self.pos += 1;
+                let list = self.compound_list(&["}"])?;
+                self.expect_keyword("}")?;
+                CompoundCommand::Group(list)
+            }
+            Some("function") => {
+                self.pos += 1;
+                let name = match self.peek() {
+                    Some(Token::Word(name)) if is_valid_name(name) => name.clone(),
+                    _ => return Err(self.unexpected()),
+                };
+                self.pos += 1;
+                let parens = self.peek_op() == Some(Operator::LParen);
+                if parens {
+                    self.pos += 1;
+                }
+                return self.function_body(name, parens);
+            }
+            Some("if") => self.if_clause()?,
+            Some("while") => self.while_clause(false)?,
+            Some("until") => self.while_clause(true)?,
+            Some("for") => self.for_clause()?,
+            Some("case") => self.case_clause()?,
+            // `then`, `fi`, `done`... out of place
+            Some(_) => return Err(self.unexpected()),
+            None => {
+                let simple = self.simple_command()?;
+                if simple.words.is_empty()
+                    && simple.assignments.is_empty()
+                    && simple.redirects.is_empty()
+                {
+                    return Err(self.unexpected());
+                }
+                return Ok(Command::Simple(simple));
+            }
+        };
+        let mut redirects = vec![];
+        loop {
+            match self.peek() {
+                Some(Token::IoNumber(fd)) => {
+                    let fd = Some(*fd);
+                    self.pos += 1;
+                    redirects.push(self.redirect(fd)?);
+                }
+                Some(Token::Op(op)) if is_redirect(*op) => redirects.push(self.redirect(None)?),
+                _ => break,
+            }
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    // function_body := [')'] linebreak compound_command redirect*
+    //
+    // After `name (`, or `function name` with or without the `(`
+    fn function_body(&mut self, name: String, parens: bool) -> Result<Command, ParseError> {
+        if parens {
+            if self.peek_op() != Some(Operator::RParen) {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        let is_compound = matches!(
+            self.peek_keyword(),
+            Some("{" | "if" | "while" | "until" | "for" | "case")
+        );
+        if !is_compound {
+            return Err(self.unexpected());
+        }
+        Ok(Command::Function(name, Box::new(self.command()?)))
+    }
+
+    // List inside a compound command, up to one of the `end` keywords. It
+    // needs at least one command.
+    fn compound_list(&mut self, end: &[&str]) -> Result<List, ParseError> {
+        let list = self.list(end)?;
+        if list.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    // if_clause := 'if' list 'then' list ('elif' list 'then' list)*
+    //              ['else' list] 'fi'
+    fn if_clause(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let mut branches = vec![];
+        let mut else_body = None;
+        loop {
+            let condition = self.compound_list(&["then"])?;
+            self.expect_keyword("then")?;
+            let body = self.compound_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            match self.peek_keyword() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    else_body = Some(self.compound_list(&["fi"])?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.expect_keyword("fi")?;
+        Ok(CompoundCommand::If {
+            branches,
+            else_body,
+        })
+    }
+
+    // while_clause := ('while' | 'until') list 'do' list 'done'
+    fn while_clause(&mut self, until: bool) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let condition = self.compound_list(&["do"])?;
+        let body = self.do_group()?;
+        Ok(CompoundCommand::While {
+            until,
+            condition,
+            body,
+        })
+    }
+
+    // do_group := 'do' list 'done'
+    fn do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_keyword("do")?;
+        let body = self.compound_list(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    // for_clause := 'for' NAME [linebreak 'in' WORD* (';' | NEWLINE)]
+    //               linebreak do_group
+    fn for_clause(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let name = match self.peek() {
+            Some(Token::Word(name)) if is_valid_name(name) => name.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        let mut words = None;
+        if self.peek() == Some(&Token::Word("in".to_string())) {
+            self.pos += 1;
+            let mut list = vec![];
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.pos += 1;
+            }
+            words = Some(list);
+            match self.peek() {
+                Some(Token::Newline) | Some(Token::Op(Operator::Semi)) => self.pos += 1,
+                _ => return Err(self.unexpected()),
+            }
+        } else if self.peek_op() == Some(Operator::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        let body = self.do_group()?;
+        Ok(CompoundCommand::For { name, words, body })
+    }
+
+    // case_clause := 'case' WORD linebreak 'in' linebreak case_item* 'esac'
+    // case_item := ['('] WORD ('|' WORD)* ')' list [';;'] linebreak
+    fn case_clause(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.pos += 1;
+        let word = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+        let mut items = vec![];
+        loop {
+            self.skip_newlines();
+            if self.peek_keyword() == Some("esac") {
+                break;
+            }
+            if self.peek_op() == Some(Operator::LParen) {
+                self.pos += 1;
+            }
+            let mut patterns = vec![];
+            loop {
+                match self.peek() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern.clone()),
+                    _ => return Err(self.unexpected()),
+                }
+                self.pos += 1;
+                match self.peek_op() {
+                    Some(Operator::Pipe) => self.pos += 1,
+                    Some(Operator::RParen) => break,
+                    _ => return Err(self.unexpected()),
+                }
+            }
+            self.pos += 1;
+            let body = self.list(&["esac"])?;
+            items.push(CaseItem { patterns, body });
+            match self.peek_op() {
+                Some(Operator::DSemi) => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.skip_newlines();
+        self.expect_keyword("esac")?;
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    // simple_command := (ASSIGNMENT_WORD | redirect)* (WORD | redirect)*
+    fn simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek() {
+                Some(Token::Word(word)) => {
+                    // `NAME=value` words are assignments until the command name
+                    match split_assignment(word) {
+                        Some((name, value)) if command.words.is_empty() => {
+                            command.assignments.push(Assignment {
+                                name: name.to_string(),
+                                value: value.to_string(),
+                            })
+                        }
+                        _ => command.words.push(word.clone()),
+                    }
+                    self.pos += 1;
+                }
+                Some(Token::IoNumber(fd)) => {
+                    let fd = Some(*fd);
+                    self.pos += 1;
+                    command.redirects.push(self.redirect(fd)?);
+                }
+                Some(Token::Op(op)) if is_redirect(*op) => {
+                    command.redirects.push(self.redirect(None)?);
+                }
+                _ => break,
+            }
+        }
+        Ok(command)
+    }
+
+    // redirect := ('<' | '>' | '>>' | '>&' | ...) WORD | ('<<' | '<<-') HEREDOC
+    fn redirect(&mut self, fd: Option<u32>) -> Result<Redirect, ParseError> {
+        let kind = match self.peek_op() {
+            Some(Operator::Less) => RedirectKind::Input,
+            Some(Operator::Great | Operator::Clobber) => RedirectKind::Output,
+            Some(Operator::DGreat) => RedirectKind::Append,
+            Some(Operator::GreatAnd) => RedirectKind::DupOutput,
+            Some(Operator::LessAnd) => RedirectKind::DupInput,
+            Some(Operator::AndGreat) => RedirectKind::OutputAll,
+            Some(Operator::AndDGreat) => RedirectKind::AppendAll,
+            Some(Operator::TLess) => RedirectKind::HereString,
+            Some(Operator::DLess | Operator::DLessDash) => {
+                self.pos += 1;
+                return match self.peek() {
+                    Some(Token::HereDoc { body, expand }) => {
+                        let redirect = Redirect {
+                            fd,
+                            kind: RedirectKind::HereDoc { expand: *expand },
+                            target: body.clone(),
+                        };
+                        self.pos += 1;
+                        Ok(redirect)
+                    }
+                    _ => Err(self.unexpected()),
+                };
+            }
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        match self.peek() {
+            Some(Token::Word(target)) => {
+                let target = target.clone();
+                self.pos += 1;
+                Ok(Redirect { fd, kind, target })
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+fn is_redirect(op: Operator) -> bool {
+    matches!(
+        op,
+        Operator::Less
+            | Operator::Great
+            | Operator::DGreat
+            | Operator::GreatAnd
+            | Operator::LessAnd
+            | Operator::AndGreat
+            | Operator::AndDGreat
+            | Operator::Clobber
+            | Operator::DLess
+            | Operator::DLessDash
+            | Operator::TLess
+    )
+}
+
+// `((expr))` is `let "expr"`: the expression is expanded as if double quoted
+fn arithmetic_word(expr: &str) -> String {
+    let mut word = String::from("\"");
+    for c in expr.chars() {
+        if matches!(c, '"' | '\\') {
+            word.push('\\');
+        }
+        word.push(c);
+    }
+    word.push('"');
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simple(words: &[&str]) -> Command {
+        Command::Simple(SimpleCommand {
+            assignments: vec![],
+            words: words.iter().map(|w| w.to_string()).collect(),
+            redirects: vec![],
+        })
+    }
+
+    #[test]
+    fn pipelines_and_lists() {
+        let list = parse("echo a | wc -l && ls; pwd").unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list[0].first.commands,
+            vec![simple(&["echo", "a"]), simple(&["wc", "-l"])]
+        );
+        assert_eq!(list[0].rest[0].0, Connector::And);
+        assert_eq!(list[0].rest[0].1.commands, vec![simple(&["ls"])]);
+        assert_eq!(list[1].first.commands, vec![simple(&["pwd"])]);
+    }
+
+    #[test]
+    fn quoted_operators_stay_in_words() {
+        let list = parse("echo \"a|b\" 'c;d' e\\&f").unwrap();
+        assert_eq!(
+            list[0].first.commands,
+            vec![simple(&["echo", "\"a|b\"", "'c;d'", "e\\&f"])]
+        );
+    }
+
+    #[test]
+    fn redirections() {
+        let list = parse("cat a 2>> err > out").unwrap();
+        let Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(command.words, vec!["cat", "a"]);
+        assert_eq!(
+            command.redirects,
+            vec![
+                Redirect {
+                    fd: Some(2),
+                    kind: RedirectKind::Append,
+                    target: "err".to_string()
+                },
+                Redirect {
+                    fd: None,
+                    kind: RedirectKind::Output,
+                    target: "out".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let list = parse("A=1 B=\"x y\"; A=1 echo B=2").unwrap();
+        let Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert!(command.words.is_empty());
+        assert_eq!(
+            command.assignments,
+            vec![
+                Assignment {
+                    name: "A".to_string(),
+                    value: "1".to_string()
+                },
+                Assignment {
+                    name: "B".to_string(),
+                    value: "\"x y\"".to_string()
+                },
+            ]
+        );
+        let Command::Simple(command) = &list[1].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(command.assignments.len(), 1);
+        assert_eq!(command.words, vec!["echo", "B=2"]);
+    }
+
+    #[test]
+    fn fd_redirections() {
+        let list = parse("cmd <in 2>&1 3<&- >|x &>>y").unwrap();
+        let Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        let redirects: Vec<_> = command
+            .redirects
+            .iter()
+            .map(|r| (r.fd(), r.kind, r.target.as_str()))
+            .collect();
+        assert_eq!(
+            redirects,
+            vec![
+                (0, RedirectKind::Input, "in"),
+                (2, RedirectKind::DupOutput, "1"),
+                (3, RedirectKind::DupInput, "-"),
+                (1, RedirectKind::Output, "x"),
+                (1, RedirectKind::AppendAll, "y"),
+            ]
+        );
+    }
+
+    #[test]
+    fn here_documents() {
+        let list = parse("cat <<EOF <<-'X'; echo\n$a \"b\"\nEOF\n\tc\n\tX\nwc <<< w").unwrap();
+        assert_eq!(list.len(), 3);
+        let Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(
+            command.redirects,
+            vec![
+                Redirect {
+                    fd: None,
+                    kind: RedirectKind::HereDoc { expand: true },
+                    target: "$a \"b\"\n".to_string()
+                },
+                Redirect {
+                    fd: None,
+                    kind: RedirectKind::HereDoc { expand: false },
+                    target: "c\n".to_string()
+                },
+            ]
+        );
+        let Command::Simple(command) = &list[2].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(command.redirects[0].kind, RedirectKind::HereString);
+        assert_eq!(command.redirects[0].fd(), 0);
+
+        let error = parse("cat <<EOF\nline").unwrap_err();
+        assert_eq!(error, ParseError::UnterminatedHereDoc("EOF".to_string()));
+        assert!(error.is_incomplete());
+    }
+
+    #[test]
+    fn pipe_stderr() {
+        let list = parse("ls >out |& wc -l").unwrap();
+        assert_eq!(list[0].first.to_string(), "ls >out 2>&1 | wc -l");
+        let Command::Simple(command) = &list[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(command.redirects[1].fd(), 2);
+        assert_eq!(command.redirects[1].kind, RedirectKind::DupOutput);
+    }
+
+    #[test]
+    fn background_lists() {
+        let list = parse("sleep 1 & a && b &\nc").unwrap();
+        let background: Vec<bool> = list.iter().map(|and_or| and_or.background).collect();
+        assert_eq!(background, [true, true, false]);
+        assert_eq!(list[1].to_string(), "a && b");
+        assert_eq!(
+            parse("& ls"),
+            Err(ParseError::UnexpectedToken("&".to_string()))
+        );
+    }
+
+    #[test]
+    fn compound_commands() {
+        let list = parse("if a; then b\nelif c; then d; else e; fi > out").unwrap();
+        let Command::Compound(
+            CompoundCommand::If {
+                branches,
+                else_body,
+            },
+            redirects,
+        ) = &list[0].first.commands[0]
+        else {
+            panic!("expected an if command");
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(branches[1].1[0].first.commands, vec![simple(&["d"])]);
+        assert_eq!(else_body.as_ref().unwrap().len(), 1);
+        assert_eq!(redirects.len(), 1);
+
+        for source in [
+            "while a; do b; c; done",
+            "until a; do b; done",
+            "for x in a \"b c\"; do echo $x; done",
+            "for x; do echo $x; done",
+            "case $x in a | b) echo ab;; *) echo other;; esac",
+        ] {
+            assert_eq!(parse(source).unwrap()[0].to_string(), source);
+        }
+        // Reserved words only count in command position
+        let list = parse("echo if done; for for in in; do :; done").unwrap();
+        assert_eq!(
+            list[0].first.commands,
+            vec![simple(&["echo", "if", "done"])]
+        );
+    }
+
+    #[test]
+    fn function_definitions() {
+        let list =
+            parse("f() { echo $1; }; function g { f; } 2>err; function h() if a; then b; fi")
+                .unwrap();
+        let names: Vec<&str> = list
+            .iter()
+            .map(|and_or| match &and_or.first.commands[0] {
+                Command::Function(name, _) => name.as_str(),
+                _ => panic!("expected a function definition"),
+            })
+            .collect();
+        assert_eq!(names, ["f", "g", "h"]);
+        assert_eq!(list[1].to_string(), "g () { f; } 2>err");
+
+        let list = parse("f() {\n  cat <<-EOF\n\tline\n\tEOF\n  echo $@\n}").unwrap();
+        let Command::Function(name, body) = &list[0].first.commands[0] else {
+            panic!("expected a function definition");
+        };
+        assert_eq!(
+            function_definition(name, body),
+            "f ()\n{\n    cat <<EOF\nline\nEOF\n    echo $@\n}\n"
+        );
+        assert!(parse("f() {").unwrap_err().is_incomplete());
+        assert_eq!(
+            parse("f() echo"),
+            Err(ParseError::UnexpectedToken("echo".to_string()))
+        );
+    }
+
+    #[test]
+    fn incomplete_compound_commands() {
+        for source in [
+            "if a; then",
+            "while a\ndo b",
+            "for x in a b",
+            "case x in a) b;;",
+        ] {
+            assert!(parse(source).unwrap_err().is_incomplete(), "{}", source);
+        }
+        assert_eq!(
+            parse("fi"),
+            Err(ParseError::UnexpectedToken("fi".to_string()))
+        );
+        assert_eq!(
+            parse("if a; then fi"),
+            Err(ParseError::UnexpectedToken("fi".to_string()))
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(
+            parse("| ls"),
+            Err(ParseError::UnexpectedToken("|".to_string()))
+        );
+        assert_eq!(parse("ls |"), Err(ParseError::UnexpectedEof));
+        assert_eq!(parse("echo 'abc"), Err(ParseError::UnterminatedQuote('\'')));
+    }
+}
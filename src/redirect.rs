@@ -0,0 +1,349 @@
+// File descriptor redirections. Files are opened by the shell, in order,
+// then the descriptors are rearranged with `dup2` in the child or, for a
+// compound command, in the shell while it runs. A lone builtin looks them
+// up through the same table when it writes its output.
+use crate::ast::{Redirect, RedirectKind};
+use crate::builtins::{write_result, Io};
+use crate::expand::{expand_heredoc, expand_word};
+use crate::shell::Shell;
+use crate::utils::ErrorKind;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Output to the terminal, which needs `\r\n` line endings in raw mode
+struct TerminalWriter<W> {
+    inner: W,
+    raw: bool,
+}
+
+impl<W: Write> Write for TerminalWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.raw {
+            return self.inner.write(buf);
+        }
+        for (i, line) in buf.split(|&byte| byte == b'\n').enumerate() {
+            if i > 0 {
+                self.inner.write_all(b"\r\n")?;
+            }
+            self.inner.write_all(line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Standard input without a buffer, so `read` takes no more than its line
+// from a file the commands after it go on reading
+pub struct Stdin;
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { libc::read(0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(read as usize)
+    }
+}
+
+// Files the shell opens are moved at or above this descriptor, out of the
+// way of the ones a command redirects
+const FIRST_PRIVATE_FD: RawFd = 10;
+
+enum Action {
+    File(File),
+    // Copy of another descriptor of the command (`2>&1`)
+    Dup(RawFd),
+    Close,
+}
+
+// Where a descriptor of an in-process builtin ends up
+enum Sink {
+    Terminal(RawFd),
+    File(File),
+}
+
+#[derive(Default)]
+pub struct Redirections {
+    // Descriptor of the command paired with what it becomes, in order
+    actions: Vec<(RawFd, Action)>,
+}
+
+impl Redirections {
+    pub fn open(shell: &mut Shell, redirects: &[Redirect]) -> Result<Redirections, String> {
+        let mut redirections = Redirections::default();
+        // Descriptors the command has open at this point
+        let mut open_fds = vec![0, 1, 2];
+        for redirect in redirects {
+            let fd = redirect.fd() as RawFd;
+            let target = match redirect.kind {
+                RedirectKind::HereDoc { .. } => String::new(),
+                _ => expand_word(shell, &redirect.target)?,
+            };
+            let action = match redirect.kind {
+                RedirectKind::Input => {
+                    Action::File(open_file(&target, OpenOptions::new().read(true))?)
+                }
+                RedirectKind::Output => Action::File(create_file(&target, false)?),
+                RedirectKind::Append => Action::File(create_file(&target, true)?),
+                RedirectKind::OutputAll | RedirectKind::AppendAll => {
+                    let append = redirect.kind == RedirectKind::AppendAll;
+                    redirections.both_outputs(create_file(&target, append)?, &mut open_fds);
+                    continue;
+                }
+                RedirectKind::DupOutput | RedirectKind::DupInput => match target.parse::<RawFd>() {
+                    _ if target == "-" => Action::Close,
+                    Ok(source) if open_fds.contains(&source) => Action::Dup(source),
+                    Ok(_) => return Err(format!("{}: Bad file descriptor", target)),
+                    // `>&file` is `&>file`
+                    Err(_) if redirect.kind == RedirectKind::DupOutput && redirect.fd.is_none() => {
+                        redirections.both_outputs(create_file(&target, false)?, &mut open_fds);
+                        continue;
+                    }
+                    Err(_) => return Err(format!("{}: ambiguous redirect", redirect.target)),
+                },
+                RedirectKind::HereDoc { expand } => {
+                    let text = if expand {
+                        expand_heredoc(shell, &redirect.target)?
+                    } else {
+                        redirect.target.clone()
+                    };
+                    Action::File(text_file(text).map_err(|error| error_message(&error))?)
+                }
+                RedirectKind::HereString => {
+                    Action::File(text_file(target + "\n").map_err(|error| error_message(&error))?)
+                }
+            };
+            open_fds.retain(|&open| open != fd);
+            if !matches!(action, Action::Close) {
+                open_fds.push(fd);
+            }
+            redirections.actions.push((fd, action));
+        }
+        Ok(redirections)
+    }
+
+    // `&>file`: stdout to the file and stderr to the same place
+    fn both_outputs(&mut self, file: File, open_fds: &mut Vec<RawFd>) {
+        self.actions.push((1, Action::File(file)));
+        self.actions.push((2, Action::Dup(1)));
+        open_fds.extend([1, 2]);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // Rearrange the child's descriptors after its stdio is set up, just
+    // before it executes the program
+    pub fn apply(&self, command: &mut Command) {
+        if self.actions.is_empty() {
+            return;
+        }
+        let moves = self.moves();
+        unsafe {
+            command.pre_exec(move || rearrange(&moves));
+        }
+    }
+
+    // Rearrange the descriptors of this process, a forked copy of the shell
+    pub fn apply_here(&self) -> io::Result<()> {
+        rearrange(&self.moves())
+    }
+
+    // Rearrange the shell's own descriptors while it runs a command itself,
+    // then put back the ones they replaced
+    pub fn run_here<T>(&self, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        if self.actions.is_empty() {
+            return f();
+        }
+        io::stdout().flush()?;
+        let mut saved: Vec<(RawFd, Option<RawFd>)> = vec![];
+        for &(fd, _) in &self.actions {
+            if saved.iter().all(|&(saved_fd, _)| saved_fd != fd) {
+                // Closed descriptors are closed again afterwards
+                let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, FIRST_PRIVATE_FD) };
+                saved.push((fd, (copy != -1).then_some(copy)));
+            }
+        }
+        let result = self.apply_here().and_then(|()| f());
+        let _ = io::stdout().flush();
+        let restored = rearrange(&saved);
+        for (_, copy) in saved {
+            if let Some(copy) = copy {
+                unsafe { libc::close(copy) };
+            }
+        }
+        restored?;
+        result
+    }
+
+    fn moves(&self) -> Vec<(RawFd, Option<RawFd>)> {
+        self.actions
+            .iter()
+            .map(|(fd, action)| match action {
+                Action::File(file) => (*fd, Some(file.as_raw_fd())),
+                Action::Dup(source) => (*fd, Some(*source)),
+                Action::Close => (*fd, None),
+            })
+            .collect()
+    }
+
+    // Run a builtin in the shell with its streams where the redirections
+    // point, `raw` when the terminal is in raw mode
+    pub fn run_builtin(
+        &self,
+        raw: bool,
+        f: impl FnOnce(&mut Io) -> io::Result<i32>,
+    ) -> io::Result<i32> {
+        let mut sinks = self.sinks()?;
+        let mut stdin: Box<dyn Read> = match sinks.remove(&0) {
+            Some(Sink::File(file)) => Box::new(file),
+            Some(Sink::Terminal(0)) => Box::new(Stdin),
+            // Closed, or pointing at the terminal's output
+            _ => Box::new(io::empty()),
+        };
+        let writer = |sink: Option<Sink>| -> Box<dyn Write> {
+            match sink {
+                Some(Sink::File(file)) => Box::new(file),
+                // Unless the shell itself redirected it away from the terminal
+                Some(Sink::Terminal(1)) => Box::new(TerminalWriter {
+                    inner: io::stdout(),
+                    raw: raw && io::stdout().is_terminal(),
+                }),
+                Some(Sink::Terminal(2)) => Box::new(TerminalWriter {
+                    inner: io::stderr(),
+                    raw: raw && io::stderr().is_terminal(),
+                }),
+                // Closed, or pointing at the terminal's input
+                _ => Box::new(io::sink()),
+            }
+        };
+        let mut stdout = writer(sinks.remove(&1));
+        let mut stderr = writer(sinks.remove(&2));
+        f(&mut Io {
+            stdin: &mut stdin,
+            stdout: &mut stdout,
+            stderr: &mut stderr,
+        })
+    }
+
+    // Write a builtin's output and errors to where its stdout and stderr
+    // are redirected
+    pub fn write_output(&self, output: Result<String, ErrorKind>, raw: bool) -> io::Result<()> {
+        self.run_builtin(raw, |io| write_result(output, io))
+            .map(drop)
+    }
+
+    fn sinks(&self) -> io::Result<HashMap<RawFd, Sink>> {
+        let mut sinks: HashMap<RawFd, Sink> = (0..=2).map(|fd| (fd, Sink::Terminal(fd))).collect();
+        for (fd, action) in &self.actions {
+            let sink = match action {
+                Action::File(file) => Some(Sink::File(file.try_clone()?)),
+                Action::Dup(source) => match sinks.get(source) {
+                    Some(Sink::File(file)) => Some(Sink::File(file.try_clone()?)),
+                    Some(Sink::Terminal(terminal)) => Some(Sink::Terminal(*terminal)),
+                    None => None,
+                },
+                Action::Close => None,
+            };
+            match sink {
+                Some(sink) => sinks.insert(*fd, sink),
+                None => sinks.remove(fd),
+            };
+        }
+        Ok(sinks)
+    }
+}
+
+// Point each descriptor at its source, or close it when there is none
+fn rearrange(moves: &[(RawFd, Option<RawFd>)]) -> io::Result<()> {
+    for &(fd, source) in moves {
+        let result = unsafe {
+            match source {
+                Some(source) if source == fd => libc::fcntl(fd, libc::F_SETFD, 0),
+                Some(source) => libc::dup2(source, fd),
+                None => libc::close(fd),
+            }
+        };
+        if result == -1 && source.is_some() {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn create_file(path: &str, append: bool) -> Result<File, String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.truncate(true);
+    }
+    open_file(path, &options)
+}
+
+fn open_file(path: &str, options: &OpenOptions) -> Result<File, String> {
+    let file = options
+        .open(path)
+        .map_err(|error| format!("{}: {}", path, error_message(&error)))?;
+    move_to_private_fd(file).map_err(|error| format!("{}: {}", path, error_message(&error)))
+}
+
+pub fn move_to_private_fd(file: File) -> io::Result<File> {
+    let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, FIRST_PRIVATE_FD) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Unlinked temporary file holding the text, used for here-documents. A
+// pipe needs a writer, and forked copies of the shell would keep it open
+// so the reader never sees the end.
+fn text_file(text: String) -> io::Result<File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let (mut file, path) = loop {
+        let name = format!(
+            "heredoc-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = env::temp_dir().join(name);
+        let opened = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path);
+        match opened {
+            Ok(file) => break (file, path),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    };
+    fs::remove_file(path)?;
+    file.write_all(text.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    move_to_private_fd(file)
+}
+
+// `io::Error` text without the "(os error N)" suffix
+pub fn error_message(error: &io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
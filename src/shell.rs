@@ -1,10 +1,21 @@
-use std::env;
+use crate::ast::Command;
+use crate::builtins::BUILTINS;
+use crate::jobs::JobTable;
+use crate::variables::Variables;
+use std::collections::HashMap;
 use std::fs;
-use std::io::{self, stdin, stdout, Write};
-use std::path::Path;
-use termion::event::Key;
-use termion::input::TermRead;
-use termion::raw::IntoRawMode;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+// Pending `break n` or `continue n`, counting the loops left to unwind, or
+// `return n` with the function's status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    Break(usize),
+    Continue(usize),
+    Return(i32),
+}
 
 pub struct Shell {
     pub input: String,
@@ -14,6 +25,53 @@ pub struct Shell {
     pub history: Vec<String>,
     pub history_index: usize,
     pub temp_input: Option<String>,
+    // Earlier lines of a command that needs more input (an open here-document)
+    pub continuation: String,
+    // Status of the last command, `$?`
+    pub last_status: i32,
+    // Status of the last command substitution of the running command, which
+    // a command with only assignments returns
+    pub substitution_status: Option<i32>,
+    // `$$`, which stays the same in forked copies of the shell
+    pub pid: u32,
+    // Set by the `exit` builtin, stops execution and ends the REPL
+    pub exit_requested: Option<i32>,
+    // A foreground job was killed by Ctrl-C, the rest of the line is skipped
+    pub interrupted: bool,
+    // Loops the running command is nested in
+    pub loop_depth: usize,
+    // Set by `break`, `continue` and `return`, the rest of the loop or
+    // function body is skipped
+    pub jump: Option<Jump>,
+    // Functions running, innermost last
+    pub function_depth: usize,
+    pub functions: HashMap<String, Rc<Command>>,
+    // `$1`, `$2`... of the running function, or set with `set --`
+    pub positional: Vec<String>,
+    pub vars: Variables,
+    // `shopt` options for globs that match nothing
+    pub nullglob: bool,
+    pub failglob: bool,
+    // `set -o pipefail`: a pipeline fails with its last failing command
+    pub pipefail: bool,
+    // Exit status of each command of the last pipeline, `PIPESTATUS`
+    pub pipestatus: Vec<i32>,
+    // Statuses of the foreground job just waited for, they become
+    // `pipestatus` once the pipeline is done
+    pub job_statuses: Option<Vec<i32>>,
+    // Processes get their own groups and the terminal is handed to the
+    // foreground job, only in the interactive shell
+    pub job_control: bool,
+    // Descriptor of the interactive shell's terminal and its attributes from
+    // before raw mode, which command substitutions run in
+    pub terminal: Option<(RawFd, libc::termios)>,
+    pub jobs: JobTable,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Shell {
     pub fn new() -> Self {
@@ -24,6 +82,26 @@ impl Shell {
             history: vec![],
             history_index: 0,
             temp_input: None,
+            continuation: String::new(),
+            last_status: 0,
+            substitution_status: None,
+            pid: std::process::id(),
+            exit_requested: None,
+            interrupted: false,
+            loop_depth: 0,
+            jump: None,
+            function_depth: 0,
+            functions: HashMap::new(),
+            positional: vec![],
+            vars: Variables::from_env(),
+            nullglob: false,
+            failglob: false,
+            pipefail: false,
+            pipestatus: vec![],
+            job_statuses: None,
+            job_control: false,
+            terminal: None,
+            jobs: JobTable::default(),
         }
     }
 
@@ -63,7 +141,7 @@ impl Shell {
         let mut completions = Vec::new();
 
         // Get PATH directories
-        if let Ok(path_env) = env::var("PATH") {
+        if let Some(path_env) = self.vars.get("PATH") {
             for dir in path_env.split(':') {
                 if let Ok(entries) = fs::read_dir(dir) {
                     for entry in entries.flatten() {
@@ -78,8 +156,7 @@ impl Shell {
         }
 
         // Add built-in commands
-        let builtins = vec!["cd", "exit", "pwd", "echo", "export"];
-        for builtin in builtins {
+        for builtin in BUILTINS {
             if builtin.starts_with(partial) {
                 completions.push(builtin.to_string());
             }
@@ -139,12 +216,20 @@ impl Shell {
         first.chars().take(prefix_len).collect()
     }
 
+    pub fn prompt(&self) -> &'static str {
+        if self.continuation.is_empty() {
+            "$ "
+        } else {
+            "> "
+        }
+    }
+
     pub fn redraw_line<W: Write>(&self, stdout: &mut W) -> io::Result<()> {
         let char_count = self.input.chars().count();
         let move_back = char_count - self.cursor_pos;
 
         write!(stdout, "\r{}", termion::clear::CurrentLine)?;
-        write!(stdout, "$ {}", self.input)?;
+        write!(stdout, "{}{}", self.prompt(), self.input)?;
 
         // Only move cursor if we need to
         if move_back > 0 {
@@ -1,8 +1,17 @@
+use crate::ast::{
+    self, AndOrList, Assignment, CompoundCommand, Connector, List, Pipeline, Redirect,
+    SimpleCommand,
+};
 use crate::builtins::*;
+use crate::expand::*;
+use crate::jobs::{self, Job, State};
+use crate::pattern;
+use crate::redirect::{error_message, Redirections, Stdin};
+use crate::shell::{Jump, Shell};
 use std::fs::OpenOptions;
 use std::io::{self};
 use std::path::Path;
-use std::thread;
+use std::rc::Rc;
 pub struct PartialSuccess {
     pub success_data: String,
     pub error_info: String,
@@ -13,61 +22,6 @@ pub enum ErrorKind {
     CompleteFailure(String),
 }
 
-pub enum Output {
-    AppendStdErr(Vec<String>),
-    AppendStdOut(Vec<String>),
-    RedirectStdOut(Vec<String>),
-    RedirectStdErr(Vec<String>),
-    StdOut,
-}
-
-pub fn tokenize(input: &str) -> Vec<String> {
-    let mut result = Vec::new();
-    let mut current = String::new();
-    let mut quote_char = None;
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        match (c, quote_char) {
-            // Backslash outside quotes
-            ('\\', None) => {
-                if let Some(next_char) = chars.next() {
-                    current.push(next_char);
-                }
-            }
-            // Backslash inside double quotes
-            ('\\', Some('"')) => {
-                if let Some(next_char) = chars.next() {
-                    match next_char {
-                        '\\' | '$' | '"' | '\n' => current.push(next_char),
-                        _ => {
-                            current.push('\\');
-                            current.push(next_char);
-                        }
-                    }
-                }
-            }
-            // Quote handling
-            ('\'' | '"', None) => quote_char = Some(c),
-            ('"', Some('"')) | ('\'', Some('\'')) => quote_char = None,
-            // Space handling
-            (' ', None) => {
-                if !current.is_empty() {
-                    result.push(current.clone());
-                    current.clear();
-                }
-            }
-            // All other characters
-            (c, _) => current.push(c),
-        }
-    }
-
-    if !current.is_empty() {
-        result.push(current);
-    }
-
-    result
-}
 use std::fs::File;
 // The output is wrapped in a Result to allow matching on errors.
 // Returns an Iterator to the Reader of the lines of the file.
@@ -92,110 +46,99 @@ pub fn process_partial_results(
         (false, true) => Ok(results),
     }
 }
-use std::io::Read;
+use std::io::{BufRead, Write};
+use std::process::{Command, ExitStatus};
+use termion::raw::RawTerminal;
+
+// Where the executors write. The interactive terminal is in raw mode for
+// line editing, and is handed to external commands run in the foreground.
+pub trait Terminal: Write {
+    // Raw mode needs `\r\n` line endings
+    fn is_raw(&self) -> bool {
+        false
+    }
+    // Write a line of the shell's own messages
+    fn write_line(&mut self, text: &str) -> io::Result<()> {
+        let end = if self.is_raw() { "\r\n" } else { "\n" };
+        write!(self, "{}{}", text, end)
+    }
+    // Set up `command` to take over the terminal when it starts
+    fn foreground(&self, _command: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+    // Give the terminal to a running process group
+    fn hand_over(&self, _pgid: libc::pid_t) -> io::Result<()> {
+        Ok(())
+    }
+    // Take the terminal back once the foreground command is done
+    fn reclaim(&self) -> io::Result<()> {
+        Ok(())
+    }
+    // Ctrl-C typed while the shell itself runs, which raw mode leaves as
+    // input instead of a signal. Discards what else was typed.
+    fn interrupt_typed(&self) -> bool {
+        false
+    }
+}
 
-use std::io::{BufRead, BufReader, Write};
-use std::process::{Command, Stdio};
-use std::time::Duration;
+impl Terminal for io::Stdout {}
 
-fn handle_output(
-    output: Result<String, ErrorKind>,
-    io_stream: Output,
-    stdout: &mut impl Write,
-) -> io::Result<()> {
-    match io_stream {
-        Output::AppendStdErr(vec2) => {
-            let filename = vec2.iter().skip(1).next().unwrap();
-            let path = Path::new(filename);
-
-            match output {
-                Ok(correct_output) => {
-                    append_to_file(path, "").expect("Error happened");
-                    println!("{}", correct_output.as_str().trim());
-                }
-                Err(ErrorKind::CompleteFailure(error_message)) => {
-                    append_to_file(path, error_message.trim()).expect("Error happened");
-                }
-                Err(ErrorKind::PartialSuccess(partial_success)) => {
-                    append_to_file(path, &partial_success.error_info.trim())
-                        .expect("Error happened");
-                    println!("{}", partial_success.success_data.trim());
-                }
-            }
+// The terminal opened on a private descriptor, which stays put while the
+// shell redirects its own stdin and stdout
+impl Terminal for RawTerminal<File> {
+    fn is_raw(&self) -> bool {
+        true
+    }
+
+    fn foreground(&self, command: &mut Command) -> io::Result<()> {
+        self.suspend_raw_mode()?;
+        let terminal = self.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+                libc::tcsetpgrp(terminal, libc::getpgrp());
+                libc::signal(libc::SIGTTOU, libc::SIG_DFL);
+                Ok(())
+            });
         }
-        Output::AppendStdOut(vec2) => {
-            let filename = vec2.iter().skip(1).next().unwrap();
-            let path = Path::new(filename);
+        Ok(())
+    }
 
-            match output {
-                Ok(correct_output) => {
-                    append_to_file(path, correct_output.as_str().trim())?;
-                }
-                Err(ErrorKind::CompleteFailure(error_message)) => {
-                    if let Ok(_) = append_to_file(path, "") {}
-                    println!("{}", error_message.trim());
-                }
-                Err(ErrorKind::PartialSuccess(partial_success)) => {
-                    append_to_file(path, &partial_success.success_data).expect("Error happened");
-                    println!("{}", partial_success.error_info.trim());
-                }
-            }
+    fn hand_over(&self, pgid: libc::pid_t) -> io::Result<()> {
+        self.suspend_raw_mode()?;
+        unsafe {
+            libc::tcsetpgrp(self.as_raw_fd(), pgid);
         }
-        Output::RedirectStdErr(vec2) => {
-            let filename = vec2.iter().skip(1).next().unwrap();
+        Ok(())
+    }
 
-            match output {
-                Ok(correct_output) => {
-                    if !correct_output.is_empty() {
-                        println!("{}", correct_output.trim());
-                    }
-                    std::fs::write(filename, "").expect("failed");
-                }
-                Err(ErrorKind::CompleteFailure(error_message)) => {
-                    std::fs::write(filename, error_message + "\n").expect("failed");
-                }
-                Err(ErrorKind::PartialSuccess(partial_success)) => {
-                    std::fs::write(filename, partial_success.error_info + "\n").expect("failed");
-                    println!("{}", partial_success.success_data.trim());
-                }
-            }
+    fn reclaim(&self) -> io::Result<()> {
+        unsafe {
+            libc::tcsetpgrp(self.as_raw_fd(), libc::getpgrp());
         }
-        Output::RedirectStdOut(vec2) => {
-            let filename = vec2.iter().skip(1).next().unwrap();
+        // Back to the attributes saved at startup, whatever the command left
+        // behind, then raw mode on top of them
+        self.suspend_raw_mode()?;
+        self.activate_raw_mode()
+    }
 
-            match output {
-                Ok(correct_output) => std::fs::write(filename, correct_output).expect("failed"),
-                Err(ErrorKind::CompleteFailure(error_message)) => {
-                    write!(stdout, "{}", error_message.trim().replace("\n", "\r\n"))?;
-                }
-                Err(ErrorKind::PartialSuccess(partial_success)) => {
-                    std::fs::write(filename, partial_success.success_data + "\n").expect("failed");
-                    write!(
-                        stdout,
-                        "{}",
-                        partial_success.error_info.replace("\n", "\r\n")
-                    )?;
-                }
+    fn interrupt_typed(&self) -> bool {
+        let mut typed = false;
+        let mut poll_fd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        while unsafe { libc::poll(&mut poll_fd, 1, 0) } > 0 {
+            let mut byte = 0u8;
+            let read = unsafe { libc::read(self.as_raw_fd(), &mut byte as *mut u8 as _, 1) };
+            if read != 1 {
+                break;
             }
+            typed |= byte == 0x03;
         }
-        Output::StdOut => match output {
-            Ok(correct_output) => {
-                if !correct_output.is_empty() {
-                    let output = correct_output.replace("\n", "\r\n");
-                    write!(stdout, "{}\r\n", output.trim())?;
-                    stdout.flush()?;
-                }
-            }
-            Err(ErrorKind::CompleteFailure(error_message)) => {
-                eprintln!("{}", error_message.trim())
-            }
-            Err(ErrorKind::PartialSuccess(partial_success)) => {
-                println!("{}", partial_success.success_data.trim());
-                eprintln!("{}", partial_success.error_info.trim());
-            }
-        },
+        typed
     }
-    Ok(())
 }
 
 pub fn append_to_file(path: &Path, content: &str) -> std::io::Result<()> {
@@ -211,301 +154,1158 @@ pub fn append_to_file(path: &Path, content: &str) -> std::io::Result<()> {
     Ok(())
 }
 
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
-
+use std::os::unix::io::{AsRawFd, FromRawFd};
 #[cfg(unix)]
-use std::os::unix::process::CommandExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+// Exit status of a finished child, 128 + signal number when it was killed
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+// Signals the shell ignores for job control, back to their defaults in children
+pub const JOB_CONTROL_SIGNALS: [libc::c_int; 3] = [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+// External command with the shell's exported variables as its environment,
+// in a new process group when job control is on
+fn new_command(shell: &Shell, program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env_clear().envs(shell.vars.exported());
+    if shell.job_control {
+        command.process_group(0);
+    }
+
+    #[cfg(unix)]
+    unsafe {
+        command.pre_exec(|| {
+            for signal in JOB_CONTROL_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+    command
+}
+
+// Environment added by `NAME=value cmd` prefixes
+type Env = Vec<(String, String)>;
+
+// Expanded pipeline stage
+enum Stage<'a> {
+    Simple(Env, Vec<String>, Redirections),
+    Compound(&'a CompoundCommand, Redirections),
+}
+
+// Values of `NAME=value cmd` prefixes, which only apply to that command
+fn expand_assignments(shell: &mut Shell, assignments: &[Assignment]) -> Result<Env, String> {
+    assignments
+        .iter()
+        .map(|assignment| {
+            if shell.vars.is_readonly(&assignment.name) {
+                return Err(format!("{}: readonly variable", assignment.name));
+            }
+            Ok((
+                assignment.name.clone(),
+                expand_assignment(shell, &assignment.value)?,
+            ))
+        })
+        .collect()
+}
 
-// Wrapper to run commands with Ctrl+C support
-pub fn execute_with_interrupt_support(input: &str, stdout: &mut impl Write) -> io::Result<()> {
-    if input.contains('|') {
-        execute_pipeline_interruptible(input, stdout)
+// Run a builtin with prefix assignments exported, then put the old values back
+fn with_temporary_vars<T>(shell: &mut Shell, env: &Env, f: impl FnOnce(&mut Shell) -> T) -> T {
+    let mut saved = Vec::new();
+    for (name, value) in env {
+        saved.push((name, shell.vars.get_var(name)));
+        let _ = shell.vars.set(name, value.clone());
+        shell.vars.export(name);
+    }
+    let output = f(shell);
+    for (name, previous) in saved.into_iter().rev() {
+        shell.vars.restore(name, previous);
+    }
+    output
+}
+
+// Run every and-or list in order, returning the status of the last one
+pub fn execute_list(shell: &mut Shell, list: &List, stdout: &mut impl Terminal) -> io::Result<i32> {
+    let mut status = 0;
+    for and_or in list {
+        if shell.exit_requested.is_some() || shell.interrupted || shell.jump.is_some() {
+            break;
+        }
+        status = if and_or.background {
+            execute_background(shell, and_or, stdout)?
+        } else {
+            execute_and_or(shell, and_or, stdout)?
+        };
+    }
+    Ok(status)
+}
+
+// `list &`: a lone pipeline becomes a job itself, anything longer runs in a
+// forked copy of the shell
+fn execute_background(
+    shell: &mut Shell,
+    and_or: &AndOrList,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    if and_or.rest.is_empty() {
+        return execute_with_interrupt_support(shell, &and_or.first, true, stdout);
+    }
+    stdout.flush()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            jobs::install_sigchld_handler();
+            if shell.job_control {
+                unsafe { libc::setpgid(0, 0) };
+                shell.job_control = false;
+            }
+            let mut stdout = io::stdout();
+            let status = execute_and_or(shell, and_or, &mut stdout).unwrap_or(1);
+            let _ = stdout.flush();
+            unsafe { libc::_exit(shell.exit_requested.unwrap_or(status)) }
+        }
+        pid => {
+            if shell.job_control {
+                unsafe { libc::setpgid(pid, pid) };
+            }
+            let mut job = Job::new(pid, and_or.to_string());
+            job.push(pid);
+            shell.last_status = 0;
+            finish_job(shell, job, true, stdout)
+        }
+    }
+}
+
+// `a && b || c`: each pipeline runs depending on the status of the previous one
+pub fn execute_and_or(
+    shell: &mut Shell,
+    and_or: &AndOrList,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let mut status = execute_with_interrupt_support(shell, &and_or.first, false, stdout)?;
+    for (connector, pipeline) in &and_or.rest {
+        let run = match connector {
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        };
+        let stopped = shell.exit_requested.is_some() || shell.interrupted || shell.jump.is_some();
+        if run && !stopped {
+            status = execute_with_interrupt_support(shell, pipeline, false, stdout)?;
+        }
+    }
+    Ok(status)
+}
+
+// Write an error message of the shell to stderr, where the command's
+// redirections point it
+fn report_error(
+    error: &str,
+    redirections: &Redirections,
+    stdout: &mut impl Terminal,
+) -> io::Result<()> {
+    stdout.flush()?;
+    redirections.write_output(
+        Err(ErrorKind::CompleteFailure(error.to_string())),
+        stdout.is_raw(),
+    )
+}
+
+// Report a command whose words or redirections failed to expand. Its
+// status is 130 when Ctrl-C stopped a command substitution in it.
+fn expansion_error(
+    shell: &Shell,
+    error: &str,
+    redirections: &Redirections,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    report_error(error, redirections, stdout)?;
+    Ok(if shell.interrupted { 130 } else { 1 })
+}
+
+// Report a command that could not be started, with its exit status
+fn spawn_error(
+    cmd: &str,
+    error: &io::Error,
+    redirections: &Redirections,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let (message, status) = if error.kind() == io::ErrorKind::NotFound {
+        (format!("{}: command not found", cmd), 127)
     } else {
-        execute_single_interruptible(input, stdout)
+        (format!("{}: {}", cmd, error_message(error)), 126)
+    };
+    report_error(&message, redirections, stdout)?;
+    Ok(status)
+}
+
+// Words and redirections of a simple command. Both are expanded, so an
+// error in the words still goes where the command's `2>` points.
+fn expand_command(
+    shell: &mut Shell,
+    command: &SimpleCommand,
+) -> Result<(Vec<String>, Redirections), (String, Redirections)> {
+    let words = expand_words(shell, &command.words);
+    match (words, Redirections::open(shell, &command.redirects)) {
+        (Ok(parts), Ok(redirections)) => Ok((parts, redirections)),
+        (Err(error), redirections) => Err((error, redirections.unwrap_or_default())),
+        (Ok(_), Err(error)) => Err((error, Redirections::default())),
     }
 }
 
+// Wrapper to run commands with Ctrl+C support, records the status as `$?`
+pub fn execute_with_interrupt_support(
+    shell: &mut Shell,
+    pipeline: &Pipeline,
+    background: bool,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let status = match pipeline.commands.as_slice() {
+        [ast::Command::Simple(command)] => {
+            execute_single_interruptible(shell, command, background, stdout)?
+        }
+        // In the background it runs as a job
+        [ast::Command::Compound(compound, redirects)] if !background => {
+            execute_redirected_compound(shell, compound, redirects, stdout)?
+        }
+        [ast::Command::Function(name, body)] => {
+            define_function(shell, name, body);
+            0
+        }
+        _ => execute_pipeline_interruptible(shell, pipeline, background, stdout)?,
+    };
+    // Jobs leave the status of each command, anything else has just one
+    shell.pipestatus = shell.job_statuses.take().unwrap_or_else(|| vec![status]);
+    shell.last_status = status;
+    Ok(status)
+}
+
 // Single command with Ctrl+C support
-pub fn execute_single_interruptible(input: &str, stdout: &mut impl Write) -> io::Result<()> {
-    let mut parts = tokenize(input);
-
-    let io_stream = if let Some(redirect_index) = parts.iter().position(|x| x == "2>>") {
-        let vec2 = parts.split_off(redirect_index);
-        Output::AppendStdErr(vec2)
-    } else if let Some(redirect_index) = parts.iter().position(|x| x == "2>") {
-        let vec2 = parts.split_off(redirect_index);
-        Output::RedirectStdErr(vec2)
-    } else if let Some(redirect_index) = parts.iter().position(|x| x == ">>" || x == "1>>") {
-        let vec2 = parts.split_off(redirect_index);
-        Output::AppendStdOut(vec2)
-    } else if let Some(redirect_index) = parts.iter().position(|x| x == ">" || x == "1>") {
-        let vec2 = parts.split_off(redirect_index);
-        Output::RedirectStdOut(vec2)
-    } else {
-        Output::StdOut
+pub fn execute_single_interruptible(
+    shell: &mut Shell,
+    command: &SimpleCommand,
+    background: bool,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let text = command.to_string();
+    shell.substitution_status = None;
+    let (parts, redirections) = match expand_command(shell, command) {
+        Ok(expanded) => expanded,
+        Err((error, redirections)) => {
+            return expansion_error(shell, &error, &redirections, stdout);
+        }
     };
 
     if parts.is_empty() {
-        return Ok(());
+        for assignment in &command.assignments {
+            let assigned = expand_assignment(shell, &assignment.value)
+                .and_then(|value| shell.vars.set(&assignment.name, value));
+            if let Err(error) = assigned {
+                return expansion_error(shell, &error, &redirections, stdout);
+            }
+        }
+        return Ok(shell.substitution_status.take().unwrap_or(0));
     }
 
     let cmd = &parts[0];
     let args = &parts[1..];
+    let env = match expand_assignments(shell, &command.assignments) {
+        Ok(env) => env,
+        Err(error) => {
+            return expansion_error(shell, &error, &redirections, stdout);
+        }
+    };
 
-    // Handle builtins normally (they run in-process)
-    if matches!(
-        cmd.as_str(),
-        "cd" | "exit" | "pwd" | "type" | "echo" | "history"
-    ) {
-        let output = Builtins.execute(cmd, args);
-        handle_output(output, io_stream, stdout)?;
-        return Ok(());
+    // Functions and builtins in the background run as a job, in a forked
+    // copy of the shell they cannot change
+    let function = shell.functions.get(cmd).cloned();
+    if background && (function.is_some() || Builtins::is_builtin(cmd)) {
+        stdout.flush()?;
+        let mut job = Job::new(0, text);
+        let pid = fork_stage(
+            shell,
+            &job,
+            &redirections,
+            None,
+            None,
+            None,
+            |shell| match &function {
+                Some(body) => {
+                    call_function(shell, body, args, &env, &mut io::stdout()).unwrap_or(1)
+                }
+                None => forked_builtin(shell, cmd, args, &env),
+            },
+        )?;
+        add_process(&mut job, pid, background, stdout)?;
+        return finish_job(shell, job, background, stdout);
     }
 
-    // Spawn external command in new process group
-    let mut command = Command::new(cmd);
-    command
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+    // Functions come before builtins and programs
+    if let Some(body) = function {
+        stdout.flush()?;
+        return redirections.run_here(|| call_function(shell, &body, args, &env, stdout));
+    }
 
-    #[cfg(unix)]
-    unsafe {
-        command.pre_exec(|| {
-            // Create new process group
-            libc::setpgid(0, 0);
-            Ok(())
-        });
+    // `fg` waits for the job like for any foreground command
+    if cmd == "fg" {
+        return foreground_job(shell, args, stdout);
     }
 
-    let mut child = match command.spawn() {
+    // Handle builtins normally (they run in-process)
+    if Builtins::is_builtin(cmd) {
+        // `read` takes input from the terminal out of raw mode, and Ctrl-C
+        // interrupts it
+        let reads = cmd == "read";
+        if reads {
+            stdout.hand_over(unsafe { libc::getpgrp() })?;
+            jobs::catch_interrupts(true);
+        }
+        let mut status = with_temporary_vars(shell, &env, |shell| {
+            redirections.run_builtin(stdout.is_raw(), |io| Builtins.run(shell, cmd, args, io))
+        })?;
+        if reads {
+            if jobs::catch_interrupts(false) {
+                stdout.write_line("")?;
+                shell.interrupted = true;
+                status = 130;
+            }
+            stdout.reclaim()?;
+        }
+        return Ok(status);
+    }
+
+    // Spawn external command. It shares the terminal (or its redirect
+    // targets) with the shell, so output shows up live.
+    let mut command = new_command(shell, cmd);
+    command.envs(env).args(args);
+    stdout.flush()?;
+    let mut job = Job::new(0, text);
+    join_job(shell, &job, &mut command, background, stdout)?;
+    redirections.apply(&mut command);
+
+    let child = match command.spawn() {
         Ok(c) => c,
-        Err(_) => {
-            write!(stdout, "{}: command not found\r\n", cmd)?;
-            return Ok(());
+        Err(error) => {
+            stdout.reclaim()?;
+            return spawn_error(cmd, &error, &redirections, stdout);
         }
     };
+    drop(redirections);
+    add_process(&mut job, child.id() as libc::pid_t, background, stdout)?;
+    finish_job(shell, job, background, stdout)
+}
 
-    let pid = child.id() as i32;
+// Set up a command to run as part of `job`: in its process group and, in
+// the foreground, owning the terminal. Must come before redirections.
+fn join_job(
+    shell: &Shell,
+    job: &Job,
+    command: &mut Command,
+    background: bool,
+    stdout: &impl Terminal,
+) -> io::Result<()> {
+    if shell.job_control && job.pgid != 0 {
+        command.process_group(job.pgid);
+    }
+    if !background {
+        stdout.foreground(command)?;
+    }
+    Ok(())
+}
 
-    // Read output in thread
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
+// Record a started process, the first one leads the job's process group
+fn add_process(
+    job: &mut Job,
+    pid: libc::pid_t,
+    background: bool,
+    stdout: &impl Terminal,
+) -> io::Result<()> {
+    if job.pgid == 0 {
+        job.pgid = pid;
+        if !background {
+            stdout.hand_over(pid)?;
+        }
+    }
+    job.push(pid);
+    Ok(())
+}
 
-    let killed = Arc::new(AtomicBool::new(false));
-    let killed_clone = killed.clone();
+// Put the job in the table, then wait for it or, in the background,
+// announce it with its job number and last process id
+fn finish_job(
+    shell: &mut Shell,
+    job: Job,
+    background: bool,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let pid = job.last_pid().unwrap_or(0);
+    let id = shell.jobs.add(job);
+    if background {
+        stdout.write_line(&format!("[{}] {}", id, pid))?;
+        stdout.flush()?;
+        return Ok(0);
+    }
+    wait_for_job(shell, id, stdout)
+}
 
-    // Output thread
-    let output_thread = thread::spawn(move || {
-        let reader = BufReader::new(child_stdout);
-        let mut output = String::new();
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                output.push_str(&line);
-                output.push('\n');
-            }
+// Wait for a foreground job until it finishes or stops, then take the
+// terminal back. A stopped job stays in the table.
+fn wait_for_job(shell: &mut Shell, id: usize, stdout: &mut impl Terminal) -> io::Result<i32> {
+    let state = loop {
+        let Some(job) = shell.jobs.get_mut(id) else {
+            break State::Exited(0);
+        };
+        job.poll();
+        match job.state() {
+            State::Running => jobs::wait_for_sigchld(),
+            state => break state,
         }
-        output
-    });
+    };
+    stdout.reclaim()?;
+    let mut status = state.status();
+    if let Some(job) = shell.jobs.get(id) {
+        let statuses = job.statuses();
+        if shell.pipefail {
+            let failed = statuses.iter().rev().find(|&&status| status != 0);
+            status = failed.copied().unwrap_or(0);
+        }
+        shell.job_statuses = Some(statuses);
+    }
+    if state == State::Signaled(libc::SIGINT) {
+        // The terminal only echoed `^C`
+        stdout.write_line("")?;
+        shell.interrupted = true;
+    }
+    if state == State::Stopped {
+        shell.jobs.touch(id);
+        if let Some(job) = shell.jobs.get_mut(id) {
+            job.mark_reported();
+        }
+        if let Some(job) = shell.jobs.get(id) {
+            stdout.write_line("")?;
+            stdout.write_line(&shell.jobs.format(job))?;
+        }
+    } else {
+        shell.jobs.remove(id);
+    }
+    Ok(status)
+}
 
-    let error_thread = thread::spawn(move || {
-        let reader = BufReader::new(child_stderr);
-        let mut output = String::new();
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                output.push_str(&line);
-                output.push('\n');
-            }
+// `fg [job]`: continue a job in the foreground and wait for it
+fn foreground_job(
+    shell: &mut Shell,
+    args: &[String],
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    if !shell.job_control {
+        report_error("fg: no job control", &Redirections::default(), stdout)?;
+        return Ok(1);
+    }
+    let id = match shell.jobs.find(args.first().map(String::as_str)) {
+        Ok(id) => id,
+        Err(error) => {
+            report_error(&format!("fg: {}", error), &Redirections::default(), stdout)?;
+            return Ok(1);
         }
-        output
-    });
+    };
+    if let Some(job) = shell.jobs.get_mut(id) {
+        stdout.write_line(&job.command)?;
+        stdout.flush()?;
+        stdout.hand_over(job.pgid)?;
+        job.resume();
+    }
+    wait_for_job(shell, id, stdout)
+}
 
-    // Wait for process or Ctrl+C
-    loop {
-        // Check if process exited
-        match child.try_wait()? {
-            Some(_status) => break,
-            None => {
-                // Still running, sleep briefly
-                thread::sleep(Duration::from_millis(50));
+// Every stage runs in its own process, builtins in a forked copy of the
+// shell, connected by pipes
+pub fn execute_pipeline_interruptible(
+    shell: &mut Shell,
+    pipeline: &Pipeline,
+    background: bool,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    // A stage whose words or redirections fail to expand fails on its own,
+    // with its error where its redirections point
+    let mut commands: Vec<Result<Stage, (String, Redirections)>> = vec![];
+    for command in &pipeline.commands {
+        let stage = match command {
+            ast::Command::Simple(simple) => match expand_command(shell, simple) {
+                Ok((parts, _)) if parts.is_empty() => continue,
+                Ok((parts, redirections)) => match expand_assignments(shell, &simple.assignments) {
+                    Ok(env) => Ok(Stage::Simple(env, parts, redirections)),
+                    Err(error) => Err((error, redirections)),
+                },
+                Err(failed) => Err(failed),
+            },
+            ast::Command::Compound(compound, redirects) => Redirections::open(shell, redirects)
+                .map(|redirections| Stage::Compound(compound, redirections))
+                .map_err(|error| (error, Redirections::default())),
+            ast::Command::Function(name, body) => {
+                define_function(shell, name, body);
+                continue;
             }
-        }
+        };
+        commands.push(stage);
     }
 
-    // Get output
-    let stdout_str = output_thread.join().unwrap();
-    let stderr_str = error_thread.join().unwrap();
+    if commands.is_empty() {
+        return Ok(0);
+    }
 
-    let output = process_partial_results(
-        stdout_str.replace('\n', "\r\n"),
-        stderr_str.replace('\n', "\r\n"),
-    );
-    handle_output(output, io_stream, stdout)?;
+    let mut job = Job::new(0, pipeline.to_string());
+    // Read end of the pipe from the previous stage
+    let mut prev_read = None;
     stdout.flush()?;
-    Ok(())
-}
 
-// Fix pipeline to let builtins ignore stdin and just output
-pub fn execute_pipeline_interruptible(input: &str, stdout: &mut impl Write) -> io::Result<()> {
-    let commands: Vec<Vec<String>> = input
-        .split('|')
-        .map(|cmd| tokenize(cmd.trim()))
-        .filter(|parts| !parts.is_empty())
-        .collect();
+    for (i, stage) in commands.iter().enumerate() {
+        let stdin = prev_read.take();
+        let output = if i == commands.len() - 1 {
+            None
+        } else {
+            let (read, write) = pipe()?;
+            prev_read = Some(read);
+            Some(write)
+        };
+        let (env, parts, redirections) = match stage {
+            Ok(Stage::Simple(env, parts, redirections)) => (env, parts, redirections),
+            Ok(Stage::Compound(compound, redirections)) => {
+                let pid = fork_stage(
+                    shell,
+                    &job,
+                    redirections,
+                    stdin,
+                    output,
+                    prev_read.as_ref(),
+                    |shell| execute_compound(shell, compound, &mut io::stdout()).unwrap_or(1),
+                )?;
+                add_process(&mut job, pid, background, stdout)?;
+                continue;
+            }
+            Err((error, redirections)) => {
+                job.push_failed(expansion_error(shell, error, redirections, stdout)?);
+                continue;
+            }
+        };
+        let cmd = &parts[0];
+        let args = &parts[1..];
 
-    if commands.is_empty() {
-        return Ok(());
+        let spawned = if let Some(body) = shell.functions.get(cmd).cloned() {
+            fork_stage(
+                shell,
+                &job,
+                redirections,
+                stdin,
+                output,
+                prev_read.as_ref(),
+                |shell| call_function(shell, &body, args, env, &mut io::stdout()).unwrap_or(1),
+            )
+        } else if Builtins::is_builtin(cmd) {
+            fork_stage(
+                shell,
+                &job,
+                redirections,
+                stdin,
+                output,
+                prev_read.as_ref(),
+                |shell| forked_builtin(shell, cmd, args, env),
+            )
+        } else {
+            let mut command = new_command(shell, cmd);
+            command.envs(env.iter().cloned()).args(args);
+            join_job(shell, &job, &mut command, background, stdout)?;
+            if let Some(stdin) = stdin {
+                command.stdin(stdin);
+            }
+            if let Some(output) = output {
+                command.stdout(output);
+            }
+            redirections.apply(&mut command);
+            command.spawn().map(|child| child.id() as libc::pid_t)
+        };
+        // The other commands still run when one cannot start
+        match spawned {
+            Ok(pid) => add_process(&mut job, pid, background, stdout)?,
+            Err(error) => job.push_failed(spawn_error(cmd, &error, redirections, stdout)?),
+        }
     }
 
-    if commands.len() == 1 {
-        return execute_single_interruptible(input, stdout);
-    }
+    finish_job(shell, job, background, stdout)
+}
 
-    let builtins = Builtins;
-    let mut children: Vec<std::process::Child> = vec![];
-    let mut prev_stdout = None;
+// Run a builtin in a forked copy of the shell, on the process's own streams
+fn forked_builtin(shell: &mut Shell, cmd: &str, args: &[String], env: &Env) -> i32 {
+    let mut io = Io {
+        stdin: &mut Stdin,
+        stdout: &mut io::stdout(),
+        stderr: &mut io::stderr(),
+    };
+    with_temporary_vars(shell, env, |shell| Builtins.run(shell, cmd, args, &mut io)).unwrap_or(1)
+}
 
-    for (i, parts) in commands.iter().enumerate() {
-        let cmd = &parts[0];
-        let args = &parts[1..];
-        let is_last = i == commands.len() - 1;
-
-        // Check if it's a builtin
-        if matches!(
-            cmd.as_str(),
-            "cd" | "exit" | "pwd" | "type" | "echo" | "cat"
-        ) {
-            // Builtins ignore stdin and just execute normally
-            let builtin_output = match builtins.execute(cmd, args) {
-                Ok(output) => output,
-                Err(ErrorKind::CompleteFailure(err)) => {
-                    write!(stdout, "{}\r\n", err)?;
-                    for mut child in children {
-                        let _ = child.kill();
-                    }
-                    return Ok(());
-                }
-                Err(ErrorKind::PartialSuccess(partial)) => {
-                    write!(stdout, "{}\r\n", partial.error_info)?;
-                    partial.success_data
+// Run part of a job in a forked copy of the shell, with the pipe ends as
+// its stdin and stdout before its own redirections. `next_read` is the read
+// end of its stdout pipe, which it must not hold or it never gets SIGPIPE.
+fn fork_stage(
+    shell: &mut Shell,
+    job: &Job,
+    redirections: &Redirections,
+    stdin: Option<File>,
+    stdout: Option<File>,
+    next_read: Option<&File>,
+    run: impl FnOnce(&mut Shell) -> i32,
+) -> io::Result<libc::pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            unsafe {
+                if shell.job_control {
+                    libc::setpgid(0, job.pgid);
                 }
-            };
-
-            if is_last {
-                // Last command - output to terminal
-                if !builtin_output.is_empty() {
-                    write!(stdout, "{}\r\n", builtin_output.replace('\n', "\r\n"))?;
+                for signal in JOB_CONTROL_SIGNALS.into_iter().chain([libc::SIGPIPE]) {
+                    libc::signal(signal, libc::SIG_DFL);
                 }
-                stdout.flush()?;
-
-                // Wait for any previous processes
-                for mut child in children {
-                    let _ = child.wait();
+                if let Some(stdin) = &stdin {
+                    libc::dup2(stdin.as_raw_fd(), 0);
                 }
-                return Ok(());
-            } else {
-                // Builtin in middle of pipe - use echo to pass output to next command
-                let mut command = Command::new("sh");
-                command.arg("-c");
-                command.arg(format!(
-                    "echo -n '{}'",
-                    builtin_output.replace("'", "'\\''")
-                ));
-
-                #[cfg(unix)]
-                unsafe {
-                    command.pre_exec(|| {
-                        libc::setpgid(0, 0);
-                        Ok(())
-                    });
+                if let Some(stdout) = &stdout {
+                    libc::dup2(stdout.as_raw_fd(), 1);
                 }
-
-                // Hook up stdin from previous (but builtin ignores it)
-                if let Some(prev) = prev_stdout.take() {
-                    command.stdin(prev);
-                } else {
-                    command.stdin(Stdio::null());
+                // Never dropped, the child ends with `_exit`
+                if let Some(next_read) = next_read {
+                    libc::close(next_read.as_raw_fd());
                 }
+            }
+            drop((stdin, stdout));
+            if let Err(error) = redirections.apply_here() {
+                eprintln!("{}", error_message(&error));
+                unsafe { libc::_exit(1) }
+            }
+            jobs::install_sigchld_handler();
+            shell.job_control = false;
+            let status = run(shell);
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(shell.exit_requested.unwrap_or(status)) }
+        }
+        pid => {
+            if shell.job_control {
+                let pgid = if job.pgid == 0 { pid } else { job.pgid };
+                unsafe { libc::setpgid(pid, pgid) };
+            }
+            Ok(pid)
+        }
+    }
+}
 
-                command.stdout(Stdio::piped());
+// Run a compound command in the shell itself, which takes on its
+// redirections until it is done, so variables it sets, `cd` and `exit`
+// still count afterwards
+fn execute_redirected_compound(
+    shell: &mut Shell,
+    compound: &CompoundCommand,
+    redirects: &[Redirect],
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let redirections = match Redirections::open(shell, redirects) {
+        Ok(redirections) => redirections,
+        Err(error) => {
+            return expansion_error(shell, &error, &Redirections::default(), stdout);
+        }
+    };
+    stdout.flush()?;
+    redirections.run_here(|| execute_compound(shell, compound, stdout))
+}
 
-                match command.spawn() {
-                    Ok(mut child) => {
-                        prev_stdout = child.stdout.take().map(Stdio::from);
-                        children.push(child);
-                    }
-                    Err(_) => {
-                        write!(stdout, "Error: failed to pipe builtin output\r\n")?;
-                        return Ok(());
-                    }
+// Run an `if`, loop or `case` in the shell itself
+pub fn execute_compound(
+    shell: &mut Shell,
+    compound: &CompoundCommand,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    match compound {
+        CompoundCommand::Group(list) => execute_list(shell, list, stdout),
+        CompoundCommand::If {
+            branches,
+            else_body,
+        } => {
+            for (condition, body) in branches {
+                let status = execute_list(shell, condition, stdout)?;
+                if shell.interrupted || shell.jump.is_some() {
+                    return Ok(status);
+                }
+                if status == 0 {
+                    return execute_list(shell, body, stdout);
                 }
             }
-        } else {
-            // External command
-            let mut command = Command::new(cmd);
-            command.args(args);
-
-            #[cfg(unix)]
-            unsafe {
-                command.pre_exec(|| {
-                    libc::setpgid(0, 0);
-                    Ok(())
-                });
+            match else_body {
+                Some(body) => execute_list(shell, body, stdout),
+                None => Ok(0),
             }
-
-            if let Some(prev) = prev_stdout.take() {
-                command.stdin(prev);
+        }
+        CompoundCommand::While {
+            until,
+            condition,
+            body,
+        } => {
+            let mut status = 0;
+            shell.loop_depth += 1;
+            loop {
+                let tested = execute_list(shell, condition, stdout)?;
+                if !next_iteration(shell, stdout)? || (tested == 0) == *until {
+                    break;
+                }
+                status = execute_list(shell, body, stdout)?;
+                if !next_iteration(shell, stdout)? {
+                    break;
+                }
             }
-
-            if is_last {
-                command.stdout(Stdio::piped());
-                command.stderr(Stdio::piped());
-            } else {
-                command.stdout(Stdio::piped());
+            shell.loop_depth -= 1;
+            Ok(if shell.interrupted { 130 } else { status })
+        }
+        CompoundCommand::For { name, words, body } => {
+            let words = match words {
+                Some(words) => expand_words(shell, words),
+                None => Ok(shell.positional.clone()),
+            };
+            let words = match words {
+                Ok(words) => words,
+                Err(error) => {
+                    return expansion_error(shell, &error, &Redirections::default(), stdout);
+                }
+            };
+            let mut status = 0;
+            shell.loop_depth += 1;
+            for word in words {
+                if let Err(error) = shell.vars.set(name, word) {
+                    report_error(&error, &Redirections::default(), stdout)?;
+                    status = 1;
+                    break;
+                }
+                status = execute_list(shell, body, stdout)?;
+                if !next_iteration(shell, stdout)? {
+                    break;
+                }
             }
-
-            match command.spawn() {
-                Ok(mut child) => {
-                    if !is_last {
-                        prev_stdout = child.stdout.take().map(Stdio::from);
-                    }
-                    children.push(child);
+            shell.loop_depth -= 1;
+            Ok(if shell.interrupted { 130 } else { status })
+        }
+        CompoundCommand::Case { word, items } => {
+            let word = match expand_word(shell, word) {
+                Ok(word) => word,
+                Err(error) => {
+                    return expansion_error(shell, &error, &Redirections::default(), stdout);
                 }
-                Err(_) => {
-                    write!(stdout, "{}: command not found\r\n", cmd)?;
-                    for mut c in children {
-                        let _ = c.kill();
+            };
+            for item in items {
+                for pattern in &item.patterns {
+                    match expand_pattern(shell, pattern) {
+                        Ok(pattern) if pattern::matches(&pattern, &word) => {
+                            return execute_list(shell, &item.body, stdout);
+                        }
+                        Ok(_) => {}
+                        Err(error) => {
+                            return expansion_error(
+                                shell,
+                                &error,
+                                &Redirections::default(),
+                                stdout,
+                            );
+                        }
                     }
-                    return Ok(());
                 }
             }
+            Ok(0)
         }
     }
+}
 
-    // Read output from last command
-    if let Some(mut last) = children.pop() {
-        if let Some(last_stdout) = last.stdout.take() {
-            let reader = BufReader::new(last_stdout);
+fn define_function(shell: &mut Shell, name: &str, body: &ast::Command) {
+    shell
+        .functions
+        .insert(name.to_string(), Rc::new(body.clone()));
+}
 
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    write!(stdout, "{}\r\n", line)?;
-                    stdout.flush()?;
-                }
+// Run a function with its arguments as the positional parameters, in a new
+// scope for `local` variables
+fn call_function(
+    shell: &mut Shell,
+    body: &ast::Command,
+    args: &[String],
+    env: &Env,
+    stdout: &mut impl Terminal,
+) -> io::Result<i32> {
+    let positional = std::mem::replace(&mut shell.positional, args.to_vec());
+    // Loops of the caller are out of reach of `break`
+    let loop_depth = std::mem::replace(&mut shell.loop_depth, 0);
+    shell.function_depth += 1;
+    let status = with_temporary_vars(shell, env, |shell| {
+        shell.vars.push_scope();
+        let status = match body {
+            ast::Command::Compound(compound, redirects) => {
+                execute_redirected_compound(shell, compound, redirects, stdout)
+            }
+            body => {
+                let pipeline = Pipeline {
+                    commands: vec![body.clone()],
+                };
+                execute_pipeline_interruptible(shell, &pipeline, false, stdout)
             }
+        };
+        shell.vars.pop_scope();
+        status
+    });
+    shell.function_depth -= 1;
+    shell.loop_depth = loop_depth;
+    shell.positional = positional;
+    match shell.jump.take() {
+        Some(Jump::Return(status)) => Ok(status),
+        _ => status,
+    }
+}
+
+// Whether a loop goes on after running one of its lists. Takes a `break` or
+// `continue` meant for this loop and passes on one for an outer loop.
+fn next_iteration(shell: &mut Shell, stdout: &mut impl Terminal) -> io::Result<bool> {
+    match shell.jump.take() {
+        None | Some(Jump::Continue(1)) => {}
+        Some(Jump::Break(1)) => return Ok(false),
+        Some(Jump::Break(n)) => {
+            shell.jump = Some(Jump::Break(n - 1));
+            return Ok(false);
+        }
+        Some(Jump::Continue(n)) => {
+            shell.jump = Some(Jump::Continue(n - 1));
+            return Ok(false);
+        }
+        Some(jump @ Jump::Return(_)) => {
+            shell.jump = Some(jump);
+            return Ok(false);
+        }
+    }
+    if stdout.interrupt_typed() {
+        stdout.write_line("^C")?;
+        shell.interrupted = true;
+    }
+    Ok(!shell.interrupted && shell.exit_requested.is_none())
+}
+
+// Both ends of a new pipe, closed on exec
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::sync::Mutex;
+
+    // Redirected compound commands move the descriptors of the whole test
+    // process for a while
+    static DESCRIPTORS: Mutex<()> = Mutex::new(());
+
+    fn run(shell: &mut Shell, source: &str) -> i32 {
+        let _descriptors = DESCRIPTORS
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let list = parse(source).unwrap();
+        execute_list(shell, &list, &mut io::stdout()).unwrap()
+    }
+
+    #[test]
+    fn forked_stages_get_sigpipe() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(
+                &mut shell,
+                "while true; do echo y; done | head -1 >/dev/null"
+            ),
+            0
+        );
+        assert_eq!(shell.pipestatus, [128 + libc::SIGPIPE, 0]);
+    }
+
+    #[test]
+    fn compound_command_with_here_document() {
+        let mut shell = Shell::new();
+        let source = "while read l; do [ \"$l\" = b ] && exit 3; done <<X\na\nb\nX";
+        run(&mut shell, source);
+        assert_eq!(shell.exit_requested, Some(3));
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "while read l; do true; done <<X\na\nX"), 0);
+    }
+
+    #[test]
+    fn redirected_compound_command_runs_in_the_shell() {
+        let path = std::env::temp_dir().join(format!("lines-{}", std::process::id()));
+        std::fs::write(&path, "a\nb\nc\n").unwrap();
+        let mut shell = Shell::new();
+        let source = format!(
+            "n=0; while read l; do n=$((n+1)); done < {}; {{ y=5; }} >/dev/null",
+            path.display()
+        );
+        run(&mut shell, &source);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(shell.vars.get("n"), Some("3"));
+        assert_eq!(shell.vars.get("y"), Some("5"));
+
+        // Each `read` takes one line and leaves the rest to `cat`
+        run(
+            &mut shell,
+            "{ read a; read b; c=$(cat); } <<X\n1\n2\n3\n4\nX",
+        );
+        assert_eq!(shell.vars.get("c"), Some("3\n4"));
+    }
+
+    #[test]
+    fn function_with_here_string() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "f() { cat >/dev/null; read l <<< \"$1\"; return $l; }",
+        );
+        assert_eq!(run(&mut shell, "f 4 <<< hi"), 4);
+        assert_eq!(
+            run(
+                &mut shell,
+                "g() { while true; do echo y; done; }; g | head -1 >/dev/null"
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn errors_go_to_stderr() {
+        let path = std::env::temp_dir().join(format!("errors-{}", std::process::id()));
+        let mut shell = Shell::new();
+        let source = format!(
+            "nosuchcmd 2>{0}; echo ${{u:?unset}} 2>>{0}; echo | nosuchcmd 2>>{0}",
+            path.display()
+        );
+        run(&mut shell, &source);
+        let errors = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            errors,
+            "nosuchcmd: command not found\nu: unset\nnosuchcmd: command not found\n"
+        );
+
+        // Not captured by command substitution
+        run(
+            &mut shell,
+            "{ x=\"[$(nosuchcmd)]\"; y=$(echo ${u:?boom}); } 2>/dev/null",
+        );
+        assert_eq!(shell.vars.get("x"), Some("[]"));
+        assert_eq!(shell.vars.get("y"), Some(""));
+    }
+
+    #[test]
+    fn assignment_status() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "x=$(false)"), 1);
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run(&mut shell, "x=$(exit 3)$(true)"), 0);
+        assert_eq!(run(&mut shell, "false; x=$?"), 0);
+        assert_eq!(shell.vars.get("x"), Some("1"));
+        assert_eq!(run(&mut shell, "$(exit 2)"), 2);
+    }
+
+    #[test]
+    fn interrupted_command_substitution() {
+        let mut shell = Shell::new();
+        let source = "echo $(sh -c 'kill -INT $PPID'); x=1";
+        assert_eq!(run(&mut shell, source), 130);
+        assert!(shell.interrupted);
+        assert_eq!(shell.vars.get("x"), None);
+    }
+
+    #[test]
+    fn colon_true_and_false() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, ":"), 0);
+        assert_eq!(run(&mut shell, "true"), 0);
+        assert_eq!(run(&mut shell, "false"), 1);
+        assert_eq!(run(&mut shell, "true | false"), 1);
+        let source = "i=0; while :; do i=$((i+1)); if [ $i = 3 ]; then break; fi; done";
+        assert_eq!(run(&mut shell, source), 0);
+        assert_eq!(shell.vars.get("i"), Some("3"));
+    }
+
+    #[test]
+    fn background_builtins_are_forked() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "export BG=1 &"), 0);
+        run(&mut shell, "f() { FN=1; }; f &");
+        assert_eq!(shell.vars.get("BG"), None);
+        assert_eq!(shell.vars.get("FN"), None);
+        assert_eq!(shell.jobs.list().len(), 2);
+    }
+
+    #[test]
+    fn redirected_function_runs_in_the_shell() {
+        let mut shell = Shell::new();
+        run(&mut shell, "f() { v=$1; echo out; }; f 1 >/dev/null");
+        assert_eq!(shell.vars.get("v"), Some("1"));
+        run(
+            &mut shell,
+            "g() { read v; } <<< body; g; h() { read w; }; h <<< call",
+        );
+        assert_eq!(shell.vars.get("v"), Some("body"));
+        assert_eq!(shell.vars.get("w"), Some("call"));
+    }
+
+    #[test]
+    fn and_or_lists() {
+        let mut shell = Shell::new();
+        let source = "false && a=1; true || b=1; false || c=1; true && d=1; false; e=1";
+        assert_eq!(run(&mut shell, source), 0);
+        let set = |name| shell.vars.get(name).is_some();
+        assert_eq!(
+            [set("a"), set("b"), set("c"), set("d"), set("e")],
+            [false, false, true, true, true]
+        );
+        assert_eq!(run(&mut shell, "true && false || false"), 1);
+        assert_eq!(run(&mut shell, "false && true || true"), 0);
+        assert_eq!(run(&mut shell, "true; false"), 1);
+    }
+
+    #[test]
+    fn exit_status() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "false; a=$?; sh -c 'exit 7'; b=$?; nosuchcmd 2>/dev/null; c=$?; d=$?",
+        );
+        assert_eq!(shell.vars.get("a"), Some("1"));
+        assert_eq!(shell.vars.get("b"), Some("7"));
+        assert_eq!(shell.vars.get("c"), Some("127"));
+        assert_eq!(shell.vars.get("d"), Some("0"));
+        assert_eq!(run(&mut shell, "sh -c 'exit 3'"), 3);
+        assert_eq!(shell.last_status, 3);
+    }
+
+    #[test]
+    fn command_environment_prefixes() {
+        let mut shell = Shell::new();
+        let source = "FOO=bar sh -c 'test \"$FOO\" = bar'";
+        assert_eq!(run(&mut shell, source), 0);
+        assert_eq!(shell.vars.get("FOO"), None);
+        run(&mut shell, "KEPT=old; KEPT=new true; GONE=1 true");
+        assert_eq!(shell.vars.get("KEPT"), Some("old"));
+        assert_eq!(shell.vars.get("GONE"), None);
+        // Exported only for the command
+        assert_eq!(run(&mut shell, "KEPT=new sh -c 'test \"$KEPT\" = new'"), 0);
+        assert_eq!(run(&mut shell, "sh -c 'test -z \"$KEPT\"'"), 0);
+    }
+
+    #[test]
+    fn external_output_is_not_buffered() {
+        let path = std::env::temp_dir().join(format!("order-{}", std::process::id()));
+        let mut shell = Shell::new();
+        let source = format!(
+            "{{ echo a; sh -c 'echo b; echo c >&2; echo d'; echo e >&2; }} >{} 2>&1",
+            path.display()
+        );
+        assert_eq!(run(&mut shell, &source), 0);
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output, "a\nb\nc\nd\ne\n");
+    }
+
+    // Records what a foreground command does with the terminal
+    #[derive(Default)]
+    struct MockTerminal {
+        handed_over: std::cell::RefCell<Vec<libc::pid_t>>,
+        reclaimed: std::cell::Cell<usize>,
+    }
+
+    impl Write for MockTerminal {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            Ok(buffer.len())
         }
 
-        let _ = last.kill();
-        let _ = last.wait();
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-        for mut child in children {
-            let _ = child.kill();
-            let _ = child.wait();
+    impl Terminal for MockTerminal {
+        fn hand_over(&self, pgid: libc::pid_t) -> io::Result<()> {
+            self.handed_over.borrow_mut().push(pgid);
+            Ok(())
+        }
+
+        fn reclaim(&self) -> io::Result<()> {
+            self.reclaimed.set(self.reclaimed.get() + 1);
+            Ok(())
         }
     }
 
-    Ok(())
+    #[test]
+    fn foreground_job_gets_the_terminal() {
+        let _descriptors = DESCRIPTORS
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let mut shell = Shell::new();
+        shell.job_control = true;
+        let mut terminal = MockTerminal::default();
+        // The child leads its own process group
+        let list =
+            parse("sh -c 'read -r _ _ _ _ pgrp _ < /proc/$$/stat; test $pgrp = $$'").unwrap();
+        assert_eq!(execute_list(&mut shell, &list, &mut terminal).unwrap(), 0);
+        let handed_over = terminal.handed_over.borrow();
+        assert_eq!(handed_over.len(), 1);
+        assert_ne!(handed_over[0], unsafe { libc::getpgrp() });
+        assert_eq!(terminal.reclaimed.get(), 1);
+    }
+
+    #[test]
+    fn interrupted_command_stops_the_list() {
+        let mut shell = Shell::new();
+        assert_eq!(run(&mut shell, "sh -c 'kill -INT $$'; x=1"), 130);
+        assert!(shell.interrupted);
+        assert_eq!(shell.vars.get("x"), None);
+        assert_eq!(shell.last_status, 130);
+    }
+
+    #[test]
+    fn sigchld_wait_returns() {
+        jobs::install_sigchld_handler();
+        let mut shell = Shell::new();
+        let start = std::time::Instant::now();
+        assert_eq!(run(&mut shell, "sleep 0.1 | sh -c 'exit 3'"), 3);
+        assert_eq!(run(&mut shell, "sh -c 'exit 4' | sleep 0.1"), 0);
+        assert_eq!(shell.pipestatus, [4, 0]);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn binary_data_through_pipes() {
+        let path =
+            |name: &str| std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let bytes: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        std::fs::write(path("binary"), &bytes).unwrap();
+        let mut shell = Shell::new();
+        let source = format!(
+            "cat {0} | cat > {1}; cat < {0} > {2}; cat {0} | {{ cat; }} > {3}",
+            path("binary").display(),
+            path("piped").display(),
+            path("redirected").display(),
+            path("grouped").display()
+        );
+        run(&mut shell, &source);
+        for name in ["piped", "redirected", "grouped"] {
+            assert_eq!(std::fs::read(path(name)).unwrap(), bytes, "{}", name);
+            std::fs::remove_file(path(name)).unwrap();
+        }
+        std::fs::remove_file(path("binary")).unwrap();
+    }
 }
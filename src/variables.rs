@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::env;
+
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+    // `None` for names declared with `export NAME`/`readonly NAME` but never assigned
+    pub value: Option<String>,
+    // Exported variables are passed to the environment of spawned commands
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+// Shell variable table, seeded from the process environment
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: HashMap<String, Variable>,
+    // For each running function, the variables it made local with what
+    // they were before
+    scopes: Vec<Vec<(String, Option<Variable>)>>,
+}
+
+impl Variables {
+    pub fn from_env() -> Self {
+        let vars = env::vars()
+            .map(|(name, value)| {
+                (
+                    name,
+                    Variable {
+                        value: Some(value),
+                        exported: true,
+                        readonly: false,
+                    },
+                )
+            })
+            .collect();
+        Variables {
+            vars,
+            scopes: vec![],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).and_then(|var| var.value.as_deref())
+    }
+
+    // Assign a value, keeping the attributes of an existing variable
+    pub fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let var = self.vars.entry(name.to_string()).or_default();
+        if var.readonly {
+            return Err(format!("{}: readonly variable", name));
+        }
+        var.value = Some(value);
+        Ok(())
+    }
+
+    pub fn is_readonly(&self, name: &str) -> bool {
+        self.vars.get(name).is_some_and(|var| var.readonly)
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<Variable> {
+        self.vars.get(name).cloned()
+    }
+
+    // Put back a variable saved with `get_var`
+    pub fn restore(&mut self, name: &str, previous: Option<Variable>) {
+        match previous {
+            Some(var) => self.vars.insert(name.to_string(), var),
+            None => self.vars.remove(name),
+        };
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    // Put back the variables the function made local
+    pub fn pop_scope(&mut self) {
+        for (name, previous) in self.scopes.pop().unwrap_or_default().into_iter().rev() {
+            self.restore(&name, previous);
+        }
+    }
+
+    // `local NAME`: a fresh unset variable until the function returns. The
+    // functions it calls see it too.
+    pub fn make_local(&mut self, name: &str) -> Result<(), String> {
+        if self.is_readonly(name) {
+            return Err(format!("{}: readonly variable", name));
+        }
+        let previous = self.get_var(name);
+        let Some(scope) = self.scopes.last_mut() else {
+            return Err("can only be used in a function".to_string());
+        };
+        if !scope.iter().any(|(local, _)| local == name) {
+            scope.push((name.to_string(), previous));
+            self.vars.insert(name.to_string(), Variable::default());
+        }
+        Ok(())
+    }
+
+    pub fn export(&mut self, name: &str) {
+        self.vars.entry(name.to_string()).or_default().exported = true;
+    }
+
+    pub fn unexport(&mut self, name: &str) {
+        if let Some(var) = self.vars.get_mut(name) {
+            var.exported = false;
+        }
+    }
+
+    pub fn set_readonly(&mut self, name: &str) {
+        self.vars.entry(name.to_string()).or_default().readonly = true;
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<(), String> {
+        if self.is_readonly(name) {
+            return Err(format!("{}: cannot unset: readonly variable", name));
+        }
+        self.vars.remove(name);
+        Ok(())
+    }
+
+    // Name/value pairs making up the environment of spawned commands
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter_map(|(name, var)| match &var.value {
+            Some(value) if var.exported => Some((name.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+
+    pub fn sorted(&self) -> Vec<(&String, &Variable)> {
+        let mut vars: Vec<_> = self.vars.iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        vars
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Split `NAME=value` into its parts when the word is an assignment
+pub fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    is_valid_name(name).then_some((name, value))
+}
+
+// `declare -x NAME="value"` line used by `export -p` and `readonly -p`
+pub fn declaration(flag: &str, name: &str, var: &Variable) -> String {
+    match &var.value {
+        Some(value) => {
+            let mut quoted = String::new();
+            for c in value.chars() {
+                if matches!(c, '"' | '\\' | '$' | '`') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            format!("declare {} {}=\"{}\"", flag, name, quoted)
+        }
+        None => format!("declare {} {}", flag, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::Builtins;
+    use crate::shell::Shell;
+
+    fn run(shell: &mut Shell, cmd: &str, args: &[&str]) -> Option<String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Builtins.execute(shell, cmd, &args).ok()
+    }
+
+    #[test]
+    fn readonly_variables() {
+        let mut vars = Variables::default();
+        vars.set("R", "1".to_string()).unwrap();
+        vars.set_readonly("R");
+        assert_eq!(
+            vars.set("R", "2".to_string()),
+            Err("R: readonly variable".to_string())
+        );
+        assert_eq!(
+            vars.unset("R"),
+            Err("R: cannot unset: readonly variable".to_string())
+        );
+        assert_eq!(vars.get("R"), Some("1"));
+
+        let mut shell = Shell::new();
+        assert!(run(&mut shell, "readonly", &["R=1"]).is_some());
+        assert!(run(&mut shell, "export", &["R=2"]).is_none());
+        assert!(run(&mut shell, "unset", &["R"]).is_none());
+        assert_eq!(shell.vars.get("R"), Some("1"));
+    }
+
+    #[test]
+    fn exported_variables() {
+        let mut shell = Shell::new();
+        run(&mut shell, "export", &["A=1", "B"]);
+        assert!(shell
+            .vars
+            .exported()
+            .any(|(name, value)| (name, value) == ("A", "1")));
+        // Declared without a value, it is not in the environment yet
+        assert!(!shell.vars.exported().any(|(name, _)| name == "B"));
+        assert!(shell
+            .vars
+            .sorted()
+            .iter()
+            .any(|(name, var)| *name == "B" && var.exported));
+
+        run(&mut shell, "export", &["-n", "A"]);
+        assert!(!shell.vars.exported().any(|(name, _)| name == "A"));
+        assert_eq!(shell.vars.get("A"), Some("1"));
+    }
+
+    #[test]
+    fn declarations() {
+        let var = |value: Option<&str>| Variable {
+            value: value.map(String::from),
+            exported: true,
+            readonly: false,
+        };
+        assert_eq!(
+            declaration("-x", "V", &var(Some("a \"b\" $c `d` \\e"))),
+            "declare -x V=\"a \\\"b\\\" \\$c \\`d\\` \\\\e\""
+        );
+        assert_eq!(declaration("-x", "V", &var(None)), "declare -x V");
+    }
+}